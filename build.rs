@@ -0,0 +1,61 @@
+//! Embeds build metadata, used by `samurai::version`.
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+/// Runs git with the given arguments, returning its trimmed output if it
+/// succeeded.
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .map(|out| out.trim().to_string())
+}
+
+/// Returns whether the manifest is at the top of its git work tree.
+///
+/// When samurai is vendored inside another repository, git would otherwise
+/// report that repository's state instead.
+fn at_toplevel() -> bool {
+    let manifest = env::var("CARGO_MANIFEST_DIR").ok();
+    let toplevel = git(&["rev-parse", "--show-toplevel"]);
+    match (manifest, toplevel) {
+        (Some(manifest), Some(toplevel)) => {
+            let manifest = Path::new(&manifest).canonicalize().ok();
+            manifest.is_some() && manifest == Path::new(&toplevel).canonicalize().ok()
+        }
+        _ => false,
+    }
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // The git hash is unavailable when building from a packaged crate, or
+    // from within another repository, in which case it is simply left out.
+    if !at_toplevel() {
+        return;
+    }
+    if let Some(hash) = git(&["rev-parse", "--short", "HEAD"]) {
+        println!("cargo:rustc-env=SAMURAI_GIT_HASH={}", hash);
+    }
+
+    // The hash changes when HEAD is moved to another branch or commit, or
+    // when the branch it refers to gets a new commit (which may be stored
+    // loose or packed). Only existing files are watched, since Cargo would
+    // otherwise rerun this script on every build.
+    let mut watched = vec![git(&["rev-parse", "--git-path", "HEAD"])];
+    if let Some(branch) = git(&["symbolic-ref", "-q", "HEAD"]) {
+        watched.push(git(&["rev-parse", "--git-path", &branch]));
+        watched.push(git(&["rev-parse", "--git-path", "packed-refs"]));
+    }
+    for path in watched.into_iter().flatten() {
+        if Path::new(&path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! Default formats for Samurai.
+//!
+//! Each module provides a format, along with any format-specific data held by
+//! the targets parsed from it.
+
+pub mod makefile;
+
+/// The names of the provided formats, with their versions.
+pub const FORMATS: &[(&str, u32)] = &[("makefile", makefile::VERSION)];
+
+/// Returns the version of Samurai, along with the provided formats and their
+/// versions.
+pub fn version() -> String {
+    let formats = FORMATS
+        .iter()
+        .map(|(name, version)| format!("{} v{}", name, version))
+        .collect::<Vec<_>>();
+    format!("{}; formats: {}", samurai::version(), formats.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_lists_formats() {
+        let version = version();
+        assert!(version.starts_with(&samurai::version()));
+        assert!(version.ends_with("; formats: makefile v1"));
+    }
+}
@@ -0,0 +1,66 @@
+//! Support for `Makefile`-like formats.
+//!
+//! In `Makefile`s, the names of a target are the files it produces. A rule
+//! with multiple outputs can thus be referred to by any of them, although the
+//! first one is used as its primary name.
+
+use samurai::target::{Target, TargetExtra};
+
+/// The version of the format.
+///
+/// This is increased whenever the accepted syntax changes incompatibly.
+pub const VERSION: u32 = 1;
+
+/// Extraneous data for targets parsed from `Makefile`s.
+#[derive(Debug)]
+pub struct MakefileExtra {
+    /// All names of the target, including its primary name.
+    pub names: Vec<String>,
+}
+
+impl MakefileExtra {
+    /// Creates the data for a target with the given outputs, which become its
+    /// names.
+    pub fn new(outputs: &[String]) -> MakefileExtra {
+        MakefileExtra {
+            names: outputs.to_vec(),
+        }
+    }
+}
+
+impl TargetExtra for MakefileExtra {
+    fn has_name(&self, tgt: &Target, name: &str) -> bool {
+        tgt.name == name || self.names.iter().any(|n| n == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use samurai::target::MixedDeps;
+    use std::path::PathBuf;
+
+    fn target(outputs: &[&str], deps: &[&str]) -> Target {
+        let outputs = outputs.iter().map(|o| o.to_string()).collect::<Vec<_>>();
+        Target::new(
+            outputs[0].clone(),
+            outputs.clone(),
+            MixedDeps::Mixed(deps.iter().map(|d| d.to_string()).collect()),
+            Vec::new(),
+            Box::new(MakefileExtra::new(&outputs)),
+        )
+    }
+
+    #[test]
+    fn secondary_output_dependency() {
+        let list = Target::finalize_list(vec![
+            target(&["app"], &["parser.h", "main.c"]),
+            target(&["parser.c", "parser.h"], &["parser.y"]),
+        ]);
+        // `parser.h` refers to the target by its second output, and is
+        // standardized to its primary name.
+        assert_eq!(list["app"].dependencies(), &vec!["parser.c".to_string()]);
+        assert_eq!(list["app"].inputs(), &vec![PathBuf::from("main.c")]);
+        assert!(list["parser.c"].extra_as::<MakefileExtra>().is_some());
+    }
+}
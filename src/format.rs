@@ -12,7 +12,8 @@ use crate::target::Target;
 use regex::Regex;
 
 use std::error::Error;
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 /// Defines specializations for a given format.
 pub trait Format {
@@ -30,3 +31,78 @@ pub trait Format {
     /// from.
     fn parse<P: AsRef<Path>>(path: P, output: &mut Vec<Target>) -> Result<(), Self::ParseErr>;
 }
+
+/// Searches for a file to use, starting from the given directory and walking
+/// up through its parents.
+///
+/// The first directory containing a file whose name matches the given regex
+/// (usually from `Format::file_name`) is used. If multiple files in it match,
+/// the first one by name is returned.
+///
+/// A relative starting directory is resolved against the current directory
+/// first, so that its real parents are searched too.
+///
+/// Returns `None` if no such file is found up to the filesystem root, or if
+/// the starting directory does not exist.
+pub fn discover(start: &Path, name: &Regex) -> Option<PathBuf> {
+    let start = start.canonicalize().ok()?;
+    start.ancestors().find_map(|dir| {
+        let mut found = fs::read_dir(dir)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().map(|t| t.is_file()).unwrap_or(false))
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|file| file.to_str())
+                    .is_some_and(|file| name.is_match(file))
+            })
+            .collect::<Vec<_>>();
+        found.sort();
+        found.into_iter().next()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    #[test]
+    fn discover_walks_up() {
+        let dir = env::temp_dir().join(format!("samurai-discover-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("x/y")).unwrap();
+        // Only files are used, even if a directory would come first.
+        fs::create_dir(dir.join("0.mk")).unwrap();
+        for file in &["b.mk", "a.mk", "x/other"] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        let dir = dir.canonicalize().unwrap();
+
+        let mk = Regex::new(r"\.mk$").unwrap();
+        assert_eq!(discover(&dir.join("x/y"), &mk), Some(dir.join("a.mk")));
+        assert_eq!(discover(&dir, &mk), Some(dir.join("a.mk")));
+        let missing = Regex::new(r"^samurai-missing\.mk$").unwrap();
+        assert_eq!(discover(&dir.join("x/y"), &missing), None);
+        assert_eq!(discover(&dir.join("z"), &mk), None);
+    }
+
+    #[test]
+    fn discover_relative() {
+        // Tests are run from the package root.
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .canonicalize()
+            .unwrap();
+        let manifest = Regex::new(r"^Cargo\.toml$").unwrap();
+        assert_eq!(
+            discover(Path::new("."), &manifest),
+            Some(root.join("Cargo.toml"))
+        );
+        assert_eq!(
+            discover(Path::new("src"), &manifest),
+            Some(root.join("Cargo.toml"))
+        );
+    }
+}
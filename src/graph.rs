@@ -0,0 +1,161 @@
+//! Build graphs provide read-only access to the dependency graph of targets.
+//!
+//! A `BuildGraph` wraps a finalized list of targets, where each target is a
+//! node and each dependency an edge from the depending target to the
+//! dependency. This spares tools (e.g for visualization) from traversing the
+//! finalized map themselves.
+//!
+//! Targets which a target is only ordered after (see `Target::after`) are
+//! not dependencies, and so are kept apart as ordering edges. Unless stated
+//! otherwise, queries only consider dependency edges.
+
+use crate::target::Target;
+
+use std::collections::BTreeMap;
+
+/// The dependency graph of a finalized list of targets.
+#[derive(Debug)]
+pub struct BuildGraph {
+    /// The finalized targets, by primary name.
+    targets: BTreeMap<String, Target>,
+}
+
+impl BuildGraph {
+    /// Finalizes the given targets into a graph.
+    ///
+    /// Like `Target::finalize_list`, this panics on missing, cyclic or
+    /// duplicate targets.
+    pub fn new(list: Vec<Target>) -> BuildGraph {
+        BuildGraph::from_finalized(Target::finalize_list(list))
+    }
+
+    /// Wraps an already finalized list of targets.
+    pub fn from_finalized(targets: BTreeMap<String, Target>) -> BuildGraph {
+        BuildGraph { targets }
+    }
+
+    /// Returns the finalized targets, by primary name.
+    pub fn targets(&self) -> &BTreeMap<String, Target> {
+        &self.targets
+    }
+
+    /// Returns the finalized targets, consuming the graph.
+    pub fn into_targets(self) -> BTreeMap<String, Target> {
+        self.targets
+    }
+
+    /// Returns the target with the given primary name, if any.
+    pub fn get(&self, name: &str) -> Option<&Target> {
+        self.targets.get(name)
+    }
+
+    /// Returns the primary names of all targets (the nodes), in order.
+    pub fn nodes(&self) -> Vec<&str> {
+        self.targets.keys().map(|name| name.as_str()).collect()
+    }
+
+    /// Returns all dependency edges, as pairs of the depending target and
+    /// its dependency, ordered by the depending target. Ordering edges are
+    /// not included; see `order_edges`.
+    pub fn edges(&self) -> Vec<(&str, &str)> {
+        self.targets
+            .values()
+            .flat_map(|tgt| {
+                tgt.dependencies()
+                    .iter()
+                    .map(move |dep| (tgt.name.as_str(), dep.as_str()))
+            })
+            .collect()
+    }
+
+    /// Returns all ordering edges, as pairs of a target and a target it is
+    /// ordered after, ordered by the former.
+    pub fn order_edges(&self) -> Vec<(&str, &str)> {
+        self.targets
+            .values()
+            .flat_map(|tgt| {
+                tgt.after
+                    .iter()
+                    .map(move |before| (tgt.name.as_str(), before.as_str()))
+            })
+            .collect()
+    }
+
+    /// Returns the primary names of the targets which no other target
+    /// depends upon.
+    pub fn roots(&self) -> Vec<&str> {
+        Target::roots(&self.targets)
+            .into_iter()
+            .map(|tgt| tgt.name.as_str())
+            .collect()
+    }
+
+    /// Returns the primary names of the targets without dependencies.
+    pub fn leaves(&self) -> Vec<&str> {
+        self.targets
+            .values()
+            .filter(|tgt| tgt.dependencies().is_empty())
+            .map(|tgt| tgt.name.as_str())
+            .collect()
+    }
+
+    /// Returns the primary names of the direct dependencies of a target.
+    ///
+    /// Returns `None` if there is no such target.
+    pub fn dependencies(&self, name: &str) -> Option<Vec<&str>> {
+        self.targets
+            .get(name)
+            .map(|tgt| tgt.dependencies().iter().map(|dep| dep.as_str()).collect())
+    }
+
+    /// Returns the primary names of the targets directly depending upon a
+    /// target, in order. Targets only ordered after it are not included.
+    pub fn dependents(&self, name: &str) -> Vec<&str> {
+        self.targets
+            .values()
+            .filter(|tgt| tgt.dependencies().iter().any(|dep| dep == name))
+            .map(|tgt| tgt.name.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target::testing::target;
+
+    fn graph() -> BuildGraph {
+        let mut seed = target("seed", &[], &[], &[]);
+        seed.after = vec!["migrate".to_string()];
+        BuildGraph::new(vec![
+            target("app", &[], &["lib", "gen"], &[]),
+            target("lib", &[], &["gen"], &[]),
+            target("gen", &[], &[], &[]),
+            seed,
+            target("migrate", &[], &[], &[]),
+        ])
+    }
+
+    #[test]
+    fn roots_and_leaves() {
+        let graph = graph();
+        assert_eq!(graph.nodes(), vec!["app", "gen", "lib", "migrate", "seed"]);
+        // Ordering edges do not make `migrate` a dependency.
+        assert_eq!(graph.roots(), vec!["app", "migrate", "seed"]);
+        assert_eq!(graph.leaves(), vec!["gen", "migrate", "seed"]);
+    }
+
+    #[test]
+    fn edges() {
+        let graph = graph();
+        assert_eq!(
+            graph.edges(),
+            vec![("app", "lib"), ("app", "gen"), ("lib", "gen")]
+        );
+        assert_eq!(graph.order_edges(), vec![("seed", "migrate")]);
+        assert_eq!(graph.dependencies("app"), Some(vec!["lib", "gen"]));
+        assert_eq!(graph.dependencies("missing"), None);
+        assert_eq!(graph.dependents("gen"), vec!["app", "lib"]);
+        assert!(graph.dependents("migrate").is_empty());
+    }
+}
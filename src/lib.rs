@@ -2,4 +2,19 @@ extern crate custom_error;
 extern crate regex;
 
 pub mod format;
+pub mod graph;
+pub mod ninja;
+pub mod observer;
+pub mod stats;
 pub mod target;
+pub mod util;
+
+/// Returns the version of the library.
+///
+/// If known, the git commit the library was built from is included.
+pub fn version() -> String {
+    match option_env!("SAMURAI_GIT_HASH") {
+        Some(hash) => format!("{} ({})", env!("CARGO_PKG_VERSION"), hash),
+        None => env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
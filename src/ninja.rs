@@ -0,0 +1,240 @@
+//! Ninja export translates finalized targets into a Ninja build file.
+//!
+//! Each target becomes its own Ninja rule, holding its commands, and a build
+//! statement producing its outputs from its inputs. Optional outputs and stamp
+//! files become implicit outputs, dependencies become implicit inputs, and
+//! targets a target is ordered after become order-only inputs. Every target
+//! can be built by its name, through a phony build statement if necessary,
+//! just as it would be with Samurai.
+//!
+//! Ninja runs a single command per build statement, so the commands of a
+//! target are joined with `&&`, followed by any fallback commands (run if
+//! they fail). Ninja does not allow newlines within a command, and there is
+//! no general way to remove them without changing what the command does, so
+//! targets with multi-line commands cannot be exported. Targets without
+//! outputs are built under their name, and so are always run by Ninja.
+
+use crate::target::{quote, Stdin, Target};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/// Writes a Ninja build file equivalent to the given finalized targets.
+///
+/// Returns an `InvalidInput` error, before anything is written, if a command
+/// (including fallback commands and text fed to standard input) contains a
+/// newline.
+pub fn export<W: Write>(list: &BTreeMap<String, Target>, out: &mut W) -> io::Result<()> {
+    let multi_line = |tgt: &&Target| {
+        let stdin = match &tgt.stdin {
+            Some(Stdin::Text(text)) => text.contains('\n'),
+            _ => false,
+        };
+        stdin
+            || tgt
+                .commands
+                .iter()
+                .chain(tgt.on_failure.iter())
+                .any(|cmd| cmd.contains('\n'))
+    };
+    if let Some(tgt) = list.values().find(multi_line) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "A command of {} contains a newline, which Ninja does not allow",
+                tgt.name
+            ),
+        ));
+    }
+
+    writeln!(out, "# Generated by Samurai.")?;
+
+    let mut volatile = BTreeSet::new();
+    for (index, tgt) in list.values().enumerate() {
+        writeln!(out)?;
+
+        // Without commands, a phony build statement suffices.
+        let rule = if tgt.commands.is_empty() && tgt.stamp.is_none() {
+            String::from("phony")
+        } else {
+            let rule = rule_name(index, &tgt.name);
+            let mut command = tgt.script_commands(&tgt.commands);
+            if !tgt.on_failure.is_empty() && !tgt.commands.is_empty() {
+                command = format!(
+                    "{{ {}; }} || {{ {}; }}",
+                    command,
+                    tgt.script_commands(&tgt.on_failure)
+                );
+            }
+            if let Some(stamp) = &tgt.stamp {
+                let touch = format!("touch {}", quote(&stamp.to_string_lossy()));
+                command = if command.is_empty() {
+                    touch
+                } else {
+                    format!("{} && {}", command, touch)
+                };
+            }
+            writeln!(out, "rule {}", rule)?;
+            writeln!(out, "  command = {}", command.replace('$', "$$"))?;
+            writeln!(out, "  description = {}", tgt.name.replace('$', "$$"))?;
+            rule
+        };
+
+        let named = tgt.outputs.is_empty() || tgt.outputs.contains(&PathBuf::from(&tgt.name));
+        let outputs = if tgt.outputs.is_empty() {
+            escape(&tgt.name)
+        } else {
+            paths(&tgt.outputs)
+        };
+        write!(out, "build {}", outputs)?;
+        let implicit = tgt
+            .optional_outputs
+            .iter()
+            .chain(tgt.stamp.iter())
+            .cloned()
+            .collect::<Vec<_>>();
+        if !implicit.is_empty() {
+            write!(out, " | {}", paths(&implicit))?;
+        }
+        write!(out, ": {}", rule)?;
+        if !tgt.inputs().is_empty() {
+            write!(out, " {}", paths(tgt.inputs()))?;
+        }
+        let deps = tgt
+            .dependencies()
+            .iter()
+            .chain(tgt.volatile_inputs.iter())
+            .map(|dep| escape(dep))
+            .collect::<Vec<_>>();
+        if !deps.is_empty() {
+            write!(out, " | {}", deps.join(" "))?;
+        }
+        if !tgt.after.is_empty() {
+            let after = tgt
+                .after
+                .iter()
+                .map(|name| escape(name))
+                .collect::<Vec<_>>();
+            write!(out, " || {}", after.join(" "))?;
+        }
+        writeln!(out)?;
+
+        // Make the target available under its name.
+        if !named {
+            writeln!(out, "build {}: phony {}", escape(&tgt.name), outputs)?;
+        }
+
+        volatile.extend(tgt.volatile_inputs.iter());
+    }
+
+    // Volatile inputs are phony targets without inputs, which Ninja always
+    // considers dirty.
+    if !volatile.is_empty() {
+        writeln!(out)?;
+        for name in volatile {
+            writeln!(out, "build {}: phony", escape(name))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns a unique, valid Ninja rule name for a target.
+fn rule_name(index: usize, name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '.' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    format!("r{}_{}", index, name)
+}
+
+/// Escapes a path (or target name) for use in a build statement.
+fn escape(path: &str) -> String {
+    path.replace('$', "$$")
+        .replace(' ', "$ ")
+        .replace(':', "$:")
+}
+
+/// Escapes and joins a list of paths for use in a build statement.
+fn paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|p| escape(&p.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target::testing::target;
+
+    fn export_string(list: Vec<Target>) -> io::Result<String> {
+        let mut out = Vec::new();
+        export(&Target::finalize_list(list), &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut objects = target(
+            "objects",
+            &["a.o", "b:c.o"],
+            &["a.c", "my header.h"],
+            &["cc -c a.c", "echo $HOME"],
+        );
+        objects.after = vec!["setup".to_string()];
+        let mut setup = target("setup", &[], &[], &["mkdir -p out"]);
+        setup.stamp = Some("setup.stamp".into());
+        let mut all = target("all", &[], &["objects"], &[]);
+        all.volatile_inputs = vec!["FORCE".to_string()];
+
+        let expected = "\
+# Generated by Samurai.
+
+build all: phony | objects FORCE
+
+rule r1_objects
+  command = (cc -c a.c) && (echo $$HOME)
+  description = objects
+build a.o b$:c.o: r1_objects a.c my$ header.h || setup
+build objects: phony a.o b$:c.o
+
+rule r2_setup
+  command = (mkdir -p out) && touch 'setup.stamp'
+  description = setup
+build setup | setup.stamp: r2_setup
+
+build FORCE: phony
+";
+        assert_eq!(export_string(vec![objects, setup, all]).unwrap(), expected);
+    }
+
+    #[test]
+    fn fallback_and_stdin() {
+        let mut a = target("a", &["a.out"], &[], &["fast", "check"]);
+        a.on_failure = vec!["slow".to_string()];
+        a.stdin = Some(Stdin::File("a.in".into()));
+        a.stamp = Some("a.stamp".into());
+        let exported = export_string(vec![a]).unwrap();
+        assert!(exported.contains(
+            "  command = { (fast) < 'a.in' && (check) < 'a.in'; } || { (slow) < 'a.in'; } \
+             && touch 'a.stamp'\n"
+        ));
+    }
+
+    #[test]
+    fn multi_line_commands_rejected() {
+        let err = export_string(vec![target("a", &[], &[], &["echo a\necho b"])]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let mut a = target("a", &[], &[], &["cat"]);
+        a.stdin = Some(Stdin::Text("a\nb".to_string()));
+        let err = export_string(vec![a]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,386 @@
+//! Observers are notified of events as targets are updated.
+//!
+//! This decouples the presentation of a build (e.g printing progress, or
+//! displaying it in an IDE) from the update logic itself. Observers are given
+//! to `Target::update` as part of `UpdateOptions`.
+//!
+//! All methods have empty default implementations, so that an observer only
+//! needs to implement the events it is interested in.
+
+use crate::target::{Target, UpdateErr};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Receives events about targets being updated.
+pub trait BuildObserver: fmt::Debug {
+    /// Called when a target is found to be out of date, before any of its
+    /// commands are run.
+    fn on_target_start(&self, _tgt: &Target) {}
+
+    /// Called just before a command of a target is run.
+    fn on_command(&self, _tgt: &Target, _cmd: &str) {}
+
+    /// Called once a target which was out of date has been updated, whether
+    /// or not that succeeded.
+    fn on_target_finish(&self, _tgt: &Target, _result: &Result<(), UpdateErr>) {}
+
+    /// Called when a target is up to date, and so is not updated.
+    fn on_skip(&self, _tgt: &Target) {}
+}
+
+impl<T: BuildObserver + ?Sized> BuildObserver for Rc<T> {
+    fn on_target_start(&self, tgt: &Target) {
+        (**self).on_target_start(tgt)
+    }
+
+    fn on_command(&self, tgt: &Target, cmd: &str) {
+        (**self).on_command(tgt, cmd)
+    }
+
+    fn on_target_finish(&self, tgt: &Target, result: &Result<(), UpdateErr>) {
+        (**self).on_target_finish(tgt, result)
+    }
+
+    fn on_skip(&self, tgt: &Target) {
+        (**self).on_skip(tgt)
+    }
+}
+
+/// An observer which ignores all events.
+///
+/// This is the default observer.
+#[derive(Debug)]
+pub struct NoObserver;
+
+impl BuildObserver for NoObserver {}
+
+/// How much is printed while targets are updated.
+///
+/// Each level prints everything the previous one does.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only failures are printed. This is the default.
+    #[default]
+    Quiet,
+    /// Targets are printed as they are updated.
+    Normal,
+    /// Commands are printed as they are run.
+    Loud,
+    /// Targets which are up to date are printed as well.
+    Debug,
+}
+
+/// An observer which prints updated targets, their commands, and any failures
+/// to the standard error stream, depending on its verbosity.
+#[derive(Debug, Default)]
+pub struct StderrObserver {
+    /// What is printed.
+    pub verbosity: Verbosity,
+}
+
+impl StderrObserver {
+    /// Creates a new observer with the given verbosity.
+    pub fn new(verbosity: Verbosity) -> StderrObserver {
+        StderrObserver { verbosity }
+    }
+
+    /// Returns the line printed for an event, if the verbosity allows it.
+    fn message(&self, event: Event) -> Option<String> {
+        let (level, message) = match event {
+            Event::Start(tgt) => (Verbosity::Normal, tgt.name.clone()),
+            Event::Command(cmd) => (Verbosity::Loud, format!("    {}", cmd)),
+            Event::Finish(tgt, Err(err)) => {
+                (Verbosity::Quiet, format!("{} failed: {}", tgt.name, err))
+            }
+            Event::Finish(_, Ok(())) => return None,
+            Event::Skip(tgt) => (Verbosity::Debug, format!("{} is up to date", tgt.name)),
+        };
+        if self.verbosity >= level {
+            Some(message)
+        } else {
+            None
+        }
+    }
+
+    /// Prints the line for an event, if any.
+    fn print(&self, event: Event) {
+        if let Some(message) = self.message(event) {
+            eprintln!("{}", message);
+        }
+    }
+}
+
+/// An event reported by a `StderrObserver`.
+enum Event<'a> {
+    Start(&'a Target),
+    Command(&'a str),
+    Finish(&'a Target, &'a Result<(), UpdateErr>),
+    Skip(&'a Target),
+}
+
+impl BuildObserver for StderrObserver {
+    fn on_target_start(&self, tgt: &Target) {
+        self.print(Event::Start(tgt));
+    }
+
+    fn on_command(&self, _tgt: &Target, cmd: &str) {
+        self.print(Event::Command(cmd));
+    }
+
+    fn on_target_finish(&self, tgt: &Target, result: &Result<(), UpdateErr>) {
+        self.print(Event::Finish(tgt, result));
+    }
+
+    fn on_skip(&self, tgt: &Target) {
+        self.print(Event::Skip(tgt));
+    }
+}
+
+/// An observer which records how long each updated target took, for output
+/// in the Chrome tracing format.
+///
+/// The resulting file can be loaded in `chrome://tracing` or Perfetto to
+/// visualize the timeline of a build. Since the observer must be boxed into
+/// `UpdateOptions`, it is usually shared through an `Rc`, so that the trace
+/// can be written out afterwards.
+#[derive(Debug)]
+pub struct TraceObserver {
+    /// The time from which timestamps are measured.
+    epoch: Instant,
+    /// Start times of the targets currently being updated.
+    started: RefCell<HashMap<String, Instant>>,
+    /// Finished targets, with their start times and durations.
+    events: RefCell<Vec<(String, Duration, Duration)>>,
+}
+
+impl TraceObserver {
+    /// Creates a new observer, measuring time from now.
+    pub fn new() -> TraceObserver {
+        TraceObserver {
+            epoch: Instant::now(),
+            started: RefCell::new(HashMap::new()),
+            events: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Writes the recorded events as a Chrome trace.
+    ///
+    /// Each updated target is a complete (`X`) event. All events are on the
+    /// same thread, as targets are updated sequentially.
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write!(out, "{{\"traceEvents\":[")?;
+        for (i, (name, start, dur)) in self.events.borrow().iter().enumerate() {
+            if i > 0 {
+                write!(out, ",")?;
+            }
+            write!(
+                out,
+                "{{\"name\":\"{}\",\"cat\":\"target\",\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":1,\"tid\":1}}",
+                escape_json(name),
+                start.as_micros(),
+                dur.as_micros()
+            )?;
+        }
+        writeln!(out, "],\"displayTimeUnit\":\"ms\"}}")
+    }
+}
+
+impl Default for TraceObserver {
+    fn default() -> TraceObserver {
+        TraceObserver::new()
+    }
+}
+
+impl BuildObserver for TraceObserver {
+    fn on_target_start(&self, tgt: &Target) {
+        self.started
+            .borrow_mut()
+            .insert(tgt.name.clone(), Instant::now());
+    }
+
+    fn on_target_finish(&self, tgt: &Target, _result: &Result<(), UpdateErr>) {
+        if let Some(start) = self.started.borrow_mut().remove(&tgt.name) {
+            self.events.borrow_mut().push((
+                tgt.name.clone(),
+                start.duration_since(self.epoch),
+                start.elapsed(),
+            ));
+        }
+    }
+}
+
+/// Escapes a string for use within a JSON string literal.
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target::testing::target;
+    use crate::target::{StatCache, UpdateOptions};
+
+    /// An observer which records every event it receives.
+    #[derive(Debug, Default)]
+    struct Recorder(RefCell<Vec<String>>);
+
+    impl Recorder {
+        fn record(&self, event: String) {
+            self.0.borrow_mut().push(event);
+        }
+    }
+
+    impl BuildObserver for Recorder {
+        fn on_target_start(&self, tgt: &Target) {
+            self.record(format!("start {}", tgt.name));
+        }
+
+        fn on_command(&self, tgt: &Target, cmd: &str) {
+            self.record(format!("command {}: {}", tgt.name, cmd));
+        }
+
+        fn on_target_finish(&self, tgt: &Target, result: &Result<(), UpdateErr>) {
+            let status = if result.is_ok() { "ok" } else { "failed" };
+            self.record(format!("finish {} {}", tgt.name, status));
+        }
+
+        fn on_skip(&self, tgt: &Target) {
+            self.record(format!("skip {}", tgt.name));
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn event_sequence() {
+        let list = Target::finalize_list(vec![
+            target("a", &[], &["b"], &["true", "exit 3", "true"]),
+            target("b", &[], &[], &["true"]),
+        ]);
+        let update = |assume_old: &[&str]| {
+            let recorder = Rc::new(Recorder::default());
+            let opts = UpdateOptions {
+                assume_old: assume_old.iter().map(|name| name.to_string()).collect(),
+                observer: Box::new(recorder.clone()),
+                ..UpdateOptions::default()
+            };
+            assert!(list["a"].update(&list, &opts, &StatCache::new()).is_err());
+            recorder.0.replace(Vec::new())
+        };
+
+        assert_eq!(
+            update(&[]),
+            vec![
+                "start b",
+                "command b: true",
+                "finish b ok",
+                "start a",
+                "command a: true",
+                "command a: exit 3",
+                "finish a failed",
+            ]
+        );
+        assert_eq!(
+            update(&["b"]),
+            vec![
+                "skip b",
+                "start a",
+                "command a: true",
+                "command a: exit 3",
+                "finish a failed",
+            ]
+        );
+    }
+
+    #[test]
+    fn verbosity_levels() {
+        let tgt = target("a", &[], &[], &[]);
+        let (ok, failed) = (Ok(()), Err(UpdateErr::Status { status: 2 }));
+        let messages = |verbosity| {
+            let observer = StderrObserver::new(verbosity);
+            vec![
+                Event::Skip(&tgt),
+                Event::Start(&tgt),
+                Event::Command("cc a.c"),
+                Event::Finish(&tgt, &ok),
+                Event::Finish(&tgt, &failed),
+            ]
+            .into_iter()
+            .filter_map(|event| observer.message(event))
+            .collect::<Vec<_>>()
+        };
+
+        let failure = "a failed: Process exited with error code 2";
+        assert_eq!(Verbosity::default(), Verbosity::Quiet);
+        assert_eq!(messages(Verbosity::Quiet), vec![failure]);
+        assert_eq!(messages(Verbosity::Normal), vec!["a", failure]);
+        assert_eq!(messages(Verbosity::Loud), vec!["a", "    cc a.c", failure]);
+        assert_eq!(
+            messages(Verbosity::Debug),
+            vec!["a is up to date", "a", "    cc a.c", failure]
+        );
+    }
+
+    #[test]
+    fn trace_json() {
+        let write = |observer: &TraceObserver| {
+            let mut out = Vec::new();
+            observer.write(&mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let observer = TraceObserver::new();
+        assert_eq!(
+            write(&observer),
+            "{\"traceEvents\":[],\"displayTimeUnit\":\"ms\"}\n"
+        );
+
+        let (a, b) = (
+            target("a\"b\\c\n", &[], &[], &[]),
+            target("d", &[], &[], &[]),
+        );
+        observer.on_target_start(&a);
+        observer.on_target_start(&b);
+        observer.on_target_finish(&b, &Ok(()));
+        observer.on_target_finish(&a, &Err(UpdateErr::Signal));
+        // Targets which are up to date take no time.
+        observer.on_skip(&target("e", &[], &[], &[]));
+
+        let trace = write(&observer);
+        let events = trace
+            .strip_prefix("{\"traceEvents\":[{")
+            .and_then(|trace| trace.strip_suffix("}],\"displayTimeUnit\":\"ms\"}\n"))
+            .unwrap()
+            .split("},{")
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 2);
+        for (event, name) in events.iter().zip(&["d", "a\\\"b\\\\c\\u000a"]) {
+            let fields = event.split(',').collect::<Vec<_>>();
+            assert_eq!(fields.len(), 7);
+            assert_eq!(fields[0], format!("\"name\":\"{}\"", name));
+            assert_eq!(fields[1..3], ["\"cat\":\"target\"", "\"ph\":\"X\""]);
+            for (field, key) in fields[3..5].iter().zip(&["\"ts\":", "\"dur\":"]) {
+                assert!(field.strip_prefix(key).unwrap().parse::<u128>().is_ok());
+            }
+            assert_eq!(fields[5..], ["\"pid\":1", "\"tid\":1"]);
+        }
+    }
+
+    #[test]
+    fn json_escapes() {
+        assert_eq!(escape_json("plain é"), "plain é");
+        assert_eq!(escape_json("\"\\\t\u{1f}"), "\\\"\\\\\\u0009\\u001f");
+    }
+}
@@ -0,0 +1,136 @@
+//! Statistics summarize the shape of a finalized list of targets.
+//!
+//! These are useful for understanding the complexity of a build, especially
+//! when the targets are generated. The statistics can be printed as a short
+//! report using their `Display` implementation.
+
+use crate::target::Target;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+
+/// Summary metrics of a finalized list of targets.
+#[derive(Debug)]
+pub struct Stats {
+    /// Number of targets.
+    pub targets: usize,
+    /// Number of dependency edges between targets.
+    pub edges: usize,
+    /// Number of distinct input files not produced by any target.
+    pub leaf_inputs: usize,
+    /// Number of targets in the longest dependency chain.
+    pub max_depth: usize,
+    /// The longest dependency chain (the critical path), by primary name.
+    ///
+    /// The first target depends (directly) on the second, and so on.
+    pub critical_path: Vec<String>,
+}
+
+impl Stats {
+    /// Computes statistics for the given finalized list of targets.
+    pub fn of(list: &BTreeMap<String, Target>) -> Stats {
+        let mut depths = HashMap::with_capacity(list.len());
+        for tgt in list.values() {
+            depth(tgt, list, &mut depths);
+        }
+
+        // Follow the deepest dependency from the deepest target.
+        let mut critical_path = Vec::new();
+        let mut next = list
+            .values()
+            .max_by_key(|tgt| (depths[tgt.name.as_str()], &tgt.name));
+        while let Some(tgt) = next {
+            critical_path.push(tgt.name.clone());
+            next = tgt
+                .dependencies()
+                .iter()
+                .map(|dep| &list[dep])
+                .max_by_key(|dep| (depths[dep.name.as_str()], &dep.name));
+        }
+
+        let leaf_inputs = list
+            .values()
+            .flat_map(|tgt| tgt.inputs().iter())
+            .filter(|input| Target::producers(list, input).is_empty())
+            .map(|input| input.as_path())
+            .collect::<HashSet<&Path>>()
+            .len();
+
+        Stats {
+            targets: list.len(),
+            edges: list.values().map(|tgt| tgt.dependencies().len()).sum(),
+            leaf_inputs,
+            max_depth: critical_path.len(),
+            critical_path,
+        }
+    }
+}
+
+/// Computes the number of targets in the longest dependency chain starting
+/// at the given target, memoizing results.
+fn depth<'a>(
+    tgt: &'a Target,
+    list: &'a BTreeMap<String, Target>,
+    depths: &mut HashMap<&'a str, usize>,
+) -> usize {
+    if let Some(&depth) = depths.get(tgt.name.as_str()) {
+        return depth;
+    }
+
+    let deepest = tgt
+        .dependencies()
+        .iter()
+        .map(|dep| depth(&list[dep], list, depths))
+        .max()
+        .unwrap_or(0);
+    depths.insert(&tgt.name, deepest + 1);
+    deepest + 1
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Targets:       {}", self.targets)?;
+        writeln!(f, "Edges:         {}", self.edges)?;
+        writeln!(f, "Leaf inputs:   {}", self.leaf_inputs)?;
+        writeln!(f, "Max depth:     {}", self.max_depth)?;
+        write!(f, "Critical path: {}", self.critical_path.join(" -> "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target::testing::target;
+
+    #[test]
+    fn diamond() {
+        let stats = Stats::of(&Target::finalize_list(vec![
+            target("top", &[], &["left", "right"], &[]),
+            target("left", &[], &["bottom", "l.c"], &[]),
+            target("right", &[], &["bottom", "r.c", "b.out"], &[]),
+            target("bottom", &["b.out"], &["b.c"], &[]),
+        ]));
+        assert_eq!(stats.targets, 4);
+        assert_eq!(stats.edges, 4);
+        // `b.out` is produced by a target, and so is not a leaf.
+        assert_eq!(stats.leaf_inputs, 3);
+        assert_eq!(stats.max_depth, 3);
+        assert_eq!(stats.critical_path, vec!["top", "right", "bottom"]);
+    }
+
+    #[test]
+    fn chain() {
+        let stats = Stats::of(&Target::finalize_list(vec![
+            target("c", &[], &["src", "src"], &[]),
+            target("a", &[], &["b"], &[]),
+            target("b", &[], &["c"], &[]),
+            target("other", &[], &[], &[]),
+        ]));
+        assert_eq!(stats.targets, 4);
+        assert_eq!(stats.edges, 2);
+        assert_eq!(stats.leaf_inputs, 1);
+        assert_eq!(stats.max_depth, 3);
+        assert_eq!(stats.critical_path, vec!["a", "b", "c"]);
+    }
+}
@@ -11,22 +11,55 @@
 //! target, but by virtue of boxing, targets parsed from different formats can
 //! be mixed together.
 
+use crate::observer::{BuildObserver, NoObserver};
+
 use custom_error::custom_error;
+use regex::Regex;
 
-use std::collections::HashMap;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fmt;
 use std::fs;
-use std::io;
-use std::path::PathBuf;
-use std::process::Command;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Conversion into `Any`, so that `TargetExtra` can be downcast.
+///
+/// This is implemented automatically for all applicable types.
+pub trait AsAny {
+    /// Returns the value as `Any`.
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
 
 /// A uniform interface to format-specific extraneous data.
-pub trait TargetExtra {
+///
+/// The concrete type of the data can be recovered using `Target::extra_as`.
+/// It must implement `Debug`, so that targets can be debug-formatted.
+pub trait TargetExtra: AsAny + fmt::Debug {
     /// Returns whether the current target may be referred to by the given
     /// name.
     ///
     /// This is most useful to `Makefile` formats, where targets have multiple
     /// names, corresponding to output files.
     ///
+    /// Names are given exactly as parsed, and may contain any characters,
+    /// including spaces and colons. Formats which support quoting or escaping
+    /// names must remove it while parsing, so that names can be compared
+    /// verbatim here.
+    ///
     /// A reasonable default implementation has been provided.
     fn has_name(&self, tgt: &Target, name: &str) -> bool {
         tgt.name == name
@@ -38,6 +71,9 @@ pub trait TargetExtra {
 ///
 /// Useful primarily for `Makefile` formats, where dependencies may be input
 /// files or other targets.
+///
+/// Each name is a single dependency, and is never split or unquoted further.
+#[derive(Debug)]
 pub enum MixedDeps {
     Mixed(Vec<String>),
     UnMixed {
@@ -55,8 +91,8 @@ impl MixedDeps {
     /// When, however, the name is found to be a dependency, an additional name
     /// is given which is considered a "more correct" reference to it (i.e the
     /// primary name of the matching target). This is useful as it standardizes
-    /// names, allowing the result to easily reference dependencies from a hash
-    /// map of primary names.
+    /// names, allowing the result to easily reference dependencies from a map
+    /// of primary names.
     ///
     /// Panics if a dependency (from split state) is not found by the
     /// predicate.
@@ -98,23 +134,214 @@ impl MixedDeps {
     }
 }
 
+/// Resolves mixed or unmixed dependencies against a set of known targets.
+///
+/// Every name in `deps` is looked up amongst `targets`, using
+/// `TargetExtra::has_name` to match it. A name matching a target is a
+/// dependency, and is standardized to the primary name of that target. A
+/// mixed name matching no target is considered an input file, whereas an
+/// unmixed input file is kept as-is.
+///
+/// Returns the input files and the (primary) names of the dependencies, in
+/// that order.
+///
+/// Panics if an unmixed dependency does not match any of the given targets.
+pub fn resolve_dependencies<'a, I>(deps: MixedDeps, targets: I) -> (Vec<PathBuf>, Vec<String>)
+where
+    I: IntoIterator<Item = &'a Target>,
+    I::IntoIter: Clone,
+{
+    let targets = targets.into_iter();
+    deps.split(|dep| {
+        targets
+            .clone()
+            .find(|tgt| tgt.extra.has_name(tgt, &dep))
+            .map(|target| {
+                if target.name == dep {
+                    None
+                } else {
+                    Some(target.name.clone())
+                }
+            })
+    })
+}
+
+/// How the modification times of outputs are compared against inputs.
+#[derive(Debug)]
+pub enum Freshness {
+    /// The target is out of date if any output is older than the newest
+    /// input.
+    ///
+    /// This assumes that every output is produced from every input. It is
+    /// the default.
+    Any,
+    /// The target is out of date only if the newest output is older than the
+    /// newest input.
+    ///
+    /// This suits targets whose commands do not rewrite every output on each
+    /// run.
+    All,
+}
+
+/// What the commands of a target read from standard input.
+#[derive(Debug)]
+pub enum Stdin {
+    /// The contents of a file, streamed to each command.
+    File(PathBuf),
+    /// The given text.
+    Text(String),
+}
+
+/// Why a target needs to be updated.
+#[derive(Debug)]
+pub enum UpdateReason {
+    /// The target has no inputs, and so is always updated.
+    NoInputs,
+    /// An output (or the stamp file) is missing.
+    MissingOutput(PathBuf),
+    /// The (newest) input is newer than an output (or the stamp file).
+    NewerInput { input: PathBuf, output: PathBuf },
+    /// An input is missing, and assumed to be produced before the target is
+    /// updated.
+    ///
+    /// This only occurs with `UpdateOptions::ignore_missing_inputs` set.
+    MissingInput(PathBuf),
+    /// The target has a volatile input.
+    Volatile(String),
+    /// A dependency needs to be updated.
+    Dependency(String),
+}
+
+impl fmt::Display for UpdateReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpdateReason::NoInputs => write!(f, "it has no inputs"),
+            UpdateReason::MissingOutput(output) => {
+                write!(f, "output {} is missing", output.display())
+            }
+            UpdateReason::NewerInput { input, output } => write!(
+                f,
+                "input {} is newer than output {}",
+                input.display(),
+                output.display()
+            ),
+            UpdateReason::MissingInput(input) => {
+                write!(f, "input {} is missing", input.display())
+            }
+            UpdateReason::Volatile(input) => write!(f, "input {} is volatile", input),
+            UpdateReason::Dependency(dep) => write!(f, "dependency {} needs update", dep),
+        }
+    }
+}
+
 /// A format-independent method to create outputs from inputs.
 ///
 /// See the module-level documentation for more info.
+#[derive(Debug)]
 pub struct Target {
     /// Name of the target.
     pub name: String,
     /// Files produced by the target.
     pub outputs: Vec<PathBuf>,
+    /// Files which may or may not be produced by the target.
+    ///
+    /// Unlike a normal output, a missing optional output does not force an
+    /// update. An existing one is still compared against the inputs. Normal
+    /// outputs are expected to exist once the target has been updated, while
+    /// optional ones never are.
+    ///
+    /// Empty by default.
+    pub optional_outputs: Vec<PathBuf>,
+    /// How outputs are checked against inputs.
+    ///
+    /// `Freshness::Any` by default.
+    pub freshness: Freshness,
+    /// A stamp file which stands in for the outputs when checking freshness.
+    ///
+    /// If set, the inputs are compared against the stamp file rather than
+    /// the outputs, and the stamp file is touched whenever the commands
+    /// succeed. This is useful when the real outputs are hard to enumerate.
+    ///
+    /// `None` by default.
+    pub stamp: Option<PathBuf>,
+    /// Inputs which are always considered to have just been modified.
+    ///
+    /// These are names rather than files, and are never looked up on the
+    /// filesystem. Any target with such an input is out of date on every
+    /// update, as if it depended upon a phony target.
+    ///
+    /// Empty by default.
+    pub volatile_inputs: Vec<String>,
     /// Inputs and dependencies, mixed or unmixed.
     pub dependencies: MixedDeps,
     /// Commands to run.
     ///
+    /// Each command is run as a single shell invocation, even if it spans
+    /// multiple lines. As such, shell state (e.g variables) persists across
+    /// the lines of a command, but not across commands.
+    ///
     /// Due to the fact that executing a command needs to be done mutably, a
     /// whole bunch of errors come up because of the way updates are laid out.
     /// As such, a command is created and executed at the time of update, not
     /// created beforehand.
     pub commands: Vec<String>,
+    /// Whether the commands are run concurrently rather than in order.
+    ///
+    /// This is only correct if the commands are independent of each other,
+    /// which is why it is disabled by default.
+    pub parallel_commands: bool,
+    /// Fallback commands, run if any of the commands fail.
+    ///
+    /// If these succeed, the target is updated successfully regardless (e.g
+    /// when a fast path fails and a slow path is taken instead); otherwise,
+    /// their failure is reported. They are run in the same way as the
+    /// commands, i.e concurrently if `parallel_commands` is set.
+    ///
+    /// Empty by default.
+    pub on_failure: Vec<String>,
+    /// Whether commands are run in a cleaned environment.
+    ///
+    /// If set, commands only receive the variables named in `KEPT_ENV` and
+    /// `kept_env` (if they are set at all), instead of the full environment.
+    ///
+    /// Disabled by default.
+    pub clean_env: bool,
+    /// Additional environment variables to keep in a cleaned environment.
+    ///
+    /// Empty by default.
+    pub kept_env: Vec<String>,
+    /// The niceness (scheduling priority) to run commands with.
+    ///
+    /// On Unix, this is passed to `nice -n`, and so ranges from -20 (highest
+    /// priority) to 19 (lowest priority); negative values usually require
+    /// privileges. On Windows, values from 1 to 14 map to the below-normal
+    /// priority class, and values from 15 upwards to the idle priority class;
+    /// other values have no effect. Elsewhere, it is ignored.
+    ///
+    /// `None` (i.e inherited priority) by default.
+    pub nice: Option<i32>,
+    /// What the commands read from standard input.
+    ///
+    /// Every command receives the full input. `None` (i.e inherited standard
+    /// input) by default.
+    pub stdin: Option<Stdin>,
+    /// Another target which this target is an alias for.
+    ///
+    /// During finalization, the referred-to target (which may be given by any
+    /// of its names) becomes a dependency, so that updating the alias updates
+    /// it. An alias usually has no commands of its own.
+    ///
+    /// `None` by default.
+    pub alias: Option<String>,
+    /// Targets which are updated before this one, without being dependencies.
+    ///
+    /// These are pure ordering constraints (e.g running a migration before
+    /// seeding a database): they are updated first, and a failure stops the
+    /// update, but they never make this target out of date and never refer to
+    /// input files. Names are translated to primary names during finalization.
+    ///
+    /// Empty by default.
+    pub after: Vec<String>,
     /// Extraneous format-specific data.
     pub extra: Box<TargetExtra>,
 }
@@ -124,15 +351,473 @@ custom_error! {pub UpdateErr
     Io{source: io::Error} = "I/O Error",
     Status{status: i32} = "Process exited with error code {status}",
     Signal = "Process exited with signal",
+    MissingOutput{path: String} = "Output {path} was not produced",
+    File{path: String, source: io::Error} = "I/O Error on {path}",
+    Cancelled = "Update was cancelled",
+}
+
+// An error type for looking up targets by name. (Doc comments on macro
+// invocations are not rendered.)
+custom_error! {pub LookupErr
+    Pattern{source: regex::Error} = "Invalid pattern",
+    NoMatch{name: String} = "No target matches {name}",
+}
+
+/// The broad category of an `UpdateErr`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// An I/O error outside of running commands.
+    Io,
+    /// A command failed, or did not do what was expected of it.
+    Exec,
+    /// The update was cancelled.
+    Cancelled,
+}
+
+impl UpdateErr {
+    /// Returns a stable numeric code identifying the kind of error.
+    ///
+    /// | Code | Variant         | Category    |
+    /// |------|-----------------|-------------|
+    /// | 1    | `Io`            | `Io`        |
+    /// | 2    | `Status`        | `Exec`      |
+    /// | 3    | `Signal`        | `Exec`      |
+    /// | 4    | `MissingOutput` | `Exec`      |
+    /// | 5    | `File`          | `Io`        |
+    /// | 6    | `Cancelled`     | `Cancelled` |
+    ///
+    /// Codes are never reused or changed, so that scripts may rely on them.
+    pub fn code(&self) -> u32 {
+        match self {
+            UpdateErr::Io { .. } => 1,
+            UpdateErr::Status { .. } => 2,
+            UpdateErr::Signal => 3,
+            UpdateErr::MissingOutput { .. } => 4,
+            UpdateErr::File { .. } => 5,
+            UpdateErr::Cancelled => 6,
+        }
+    }
+
+    /// Returns the category of the error.
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            UpdateErr::Io { .. } | UpdateErr::File { .. } => ErrorCategory::Io,
+            UpdateErr::Status { .. } | UpdateErr::Signal | UpdateErr::MissingOutput { .. } => {
+                ErrorCategory::Exec
+            }
+            UpdateErr::Cancelled => ErrorCategory::Cancelled,
+        }
+    }
+
+    /// Returns the exit code a program should exit with due to this error.
+    ///
+    /// If a command failed, its own exit code is propagated. Otherwise, the
+    /// code is 1.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            UpdateErr::Status { status } => *status,
+            _ => 1,
+        }
+    }
+}
+
+/// Options controlling how targets are updated.
+#[derive(Debug)]
+pub struct UpdateOptions {
+    /// Whether to check that every (non-optional) output exists after the
+    /// commands of a target have run.
+    ///
+    /// Enabled by default.
+    pub verify_outputs: bool,
+    /// Targets (by primary name) which are assumed to be up to date.
+    ///
+    /// These targets are never updated, regardless of modification times,
+    /// and their dependencies are not visited through them.
+    ///
+    /// Empty by default.
+    pub assume_old: Vec<String>,
+    /// Input files which are treated as if they were just modified.
+    ///
+    /// The files themselves are not touched; only the staleness of targets
+    /// taking them as inputs is affected.
+    ///
+    /// Empty by default.
+    pub what_if: Vec<PathBuf>,
+    /// How much older than the newest input an output may be while still
+    /// being considered up to date.
+    ///
+    /// This avoids spurious updates on filesystems with coarse modification
+    /// times, where an output written shortly after its inputs may appear
+    /// older than them.
+    ///
+    /// Zero by default.
+    pub tolerance: Duration,
+    /// Whether missing inputs make a target out of date, rather than being
+    /// an error.
+    ///
+    /// This is meant for planning (e.g with `explain` or a dry run) on a tree
+    /// where intermediate files have not been generated yet. A real update
+    /// then runs the commands of such a target regardless, which will likely
+    /// fail if the input is still missing.
+    ///
+    /// Disabled by default.
+    pub ignore_missing_inputs: bool,
+    /// The observer notified of events during the update.
+    ///
+    /// `NoObserver` by default.
+    pub observer: Box<dyn BuildObserver>,
+    /// A flag which cancels the update once set (e.g from another thread).
+    ///
+    /// It is checked before each target is visited and each command is run,
+    /// and commands still running once it is set are killed. The update then
+    /// fails with `UpdateErr::Cancelled`.
+    ///
+    /// `None` by default.
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+impl Default for UpdateOptions {
+    fn default() -> UpdateOptions {
+        UpdateOptions {
+            verify_outputs: true,
+            assume_old: Vec::new(),
+            what_if: Vec::new(),
+            tolerance: Duration::from_secs(0),
+            ignore_missing_inputs: false,
+            observer: Box::new(NoObserver),
+            cancel: None,
+        }
+    }
+}
+
+impl UpdateOptions {
+    /// Returns whether the update has been cancelled.
+    fn cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::SeqCst))
+    }
+
+    /// Adds the targets listed in the given file to `assume_old`.
+    ///
+    /// The file lists one name per line. Empty lines are ignored.
+    pub fn assume_old_from<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.assume_old.extend(read_list(path.as_ref())?);
+        Ok(())
+    }
+
+    /// Adds the files listed in the given file to `what_if`.
+    ///
+    /// The file lists one path per line. Empty lines are ignored.
+    pub fn what_if_from<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.what_if
+            .extend(read_list(path.as_ref())?.into_iter().map(PathBuf::from));
+        Ok(())
+    }
+}
+
+/// Reads a newline-separated list from a file, skipping empty lines.
+fn read_list(path: &Path) -> io::Result<Vec<String>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// A cache of file modification times, for use within a single update.
+///
+/// Each path is read from the filesystem at most once, even if it is shared
+/// by multiple targets. Modification times are assumed not to change during
+/// the update, except for the outputs of targets whose commands are run,
+/// which are forgotten once the commands complete.
+///
+/// Transient errors when reading modification times (as sometimes occur on
+/// network filesystems) are retried a few times before giving up.
+#[derive(Debug)]
+pub struct StatCache {
+    mtimes: RefCell<HashMap<PathBuf, Option<SystemTime>>>,
+    retries: u32,
+}
+
+impl Default for StatCache {
+    fn default() -> StatCache {
+        StatCache::with_retries(3)
+    }
+}
+
+impl StatCache {
+    /// Creates a new, empty cache.
+    pub fn new() -> StatCache {
+        StatCache::default()
+    }
+
+    /// Creates a new, empty cache, which retries transient errors the given
+    /// number of times (at most `MAX_RETRIES`).
+    pub fn with_retries(retries: u32) -> StatCache {
+        StatCache {
+            mtimes: RefCell::new(HashMap::new()),
+            retries: retries.min(MAX_RETRIES),
+        }
+    }
+
+    /// Creates a cache seeded with previously recorded modification times.
+    ///
+    /// The given files are not read from the filesystem (unless forgotten);
+    /// all other files are read as usual.
+    pub fn with_mtimes<I>(mtimes: I) -> StatCache
+    where
+        I: IntoIterator<Item = (PathBuf, SystemTime)>,
+    {
+        let cache = StatCache::new();
+        cache
+            .mtimes
+            .borrow_mut()
+            .extend(mtimes.into_iter().map(|(path, mtime)| (path, Some(mtime))));
+        cache
+    }
+
+    /// Returns the modification time of the given file, or `None` if it could
+    /// not be read (e.g because the file does not exist).
+    pub fn modified(&self, path: &Path) -> Option<SystemTime> {
+        *self
+            .mtimes
+            .borrow_mut()
+            .entry(path.to_path_buf())
+            .or_insert_with(|| stat(path, self.retries).ok())
+    }
+
+    /// Returns the modification time of the given file, which is required to
+    /// exist.
+    ///
+    /// Unlike `modified`, the error is returned if the file cannot be read.
+    pub fn require(&self, path: &Path) -> io::Result<SystemTime> {
+        if let Some(Some(mtime)) = self.mtimes.borrow().get(path) {
+            return Ok(*mtime);
+        }
+        let mtime = stat(path, self.retries)?;
+        self.mtimes
+            .borrow_mut()
+            .insert(path.to_path_buf(), Some(mtime));
+        Ok(mtime)
+    }
+
+    /// Forgets the modification times of the given files, so that they will
+    /// be read again.
+    pub fn forget<'a, I: IntoIterator<Item = &'a PathBuf>>(&self, paths: I) {
+        let mut mtimes = self.mtimes.borrow_mut();
+        for path in paths {
+            mtimes.remove(path);
+        }
+    }
+}
+
+/// The most times a `StatCache` retries a transient error.
+pub const MAX_RETRIES: u32 = 10;
+
+/// Reads the modification time of a file, retrying transient errors the given
+/// number of times.
+fn stat(path: &Path, retries: u32) -> io::Result<SystemTime> {
+    retry(retries, || fs::metadata(path).and_then(|md| md.modified()))
+}
+
+/// Runs an operation, retrying transient errors the given number of times
+/// with a short, increasing delay.
+///
+/// The delay starts at 10ms and doubles with every attempt, up to 640ms.
+fn retry<T, F: FnMut() -> io::Result<T>>(retries: u32, mut op: F) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(ref err) if attempt < retries && is_transient(err) => {
+                thread::sleep(Duration::from_millis(10 << attempt.min(6)));
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Returns whether an I/O error may go away if the operation is retried.
+fn is_transient(err: &io::Error) -> bool {
+    // Stale NFS file handle.
+    #[cfg(target_os = "linux")]
+    const ESTALE: Option<i32> = Some(116);
+    #[cfg(not(target_os = "linux"))]
+    const ESTALE: Option<i32> = None;
+
+    match err.kind() {
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => true,
+        _ => ESTALE.is_some() && err.raw_os_error() == ESTALE,
+    }
+}
+
+/// Sets the modification time of a file to now, creating it (empty) if
+/// necessary and requested.
+///
+/// The contents of the file are left untouched.
+fn touch(path: &Path, create: bool) -> io::Result<()> {
+    fs::OpenOptions::new()
+        .create(create)
+        .append(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
+
+/// Translates a glob pattern into an equivalent (anchored) regex.
+///
+/// `*` matches any sequence of characters, `?` any single character, and
+/// `[...]` any character in the class (or, as `[!...]`, not in it). An
+/// unterminated `[` matches itself.
+///
+/// Fails if a class contains an invalid range (e.g `[z-a]`).
+fn glob_to_regex(glob: &str) -> Result<Regex, regex::Error> {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            '[' => {
+                let rest = chars.as_str();
+                let negated = rest.starts_with('!');
+                let start = if negated { 1 } else { 0 };
+                match rest[start..].find(']') {
+                    Some(len) if len > 0 => {
+                        pattern.push_str(if negated { "[^" } else { "[" });
+                        // Ranges are kept, but any other special character is
+                        // taken literally.
+                        for c in rest[start..start + len].chars() {
+                            if c == '-' {
+                                pattern.push(c);
+                            } else {
+                                pattern.push_str(&regex::escape(&c.to_string()));
+                            }
+                        }
+                        pattern.push(']');
+                        chars = rest[start + len + 1..].chars();
+                    }
+                    _ => pattern.push_str(r"\["),
+                }
+            }
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern)
+}
+
+/// Quotes a string for use as a single shell word.
+pub(crate) fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// The environment variables kept by default when commands are run in a
+/// cleaned environment.
+pub const KEPT_ENV: &[&str] = &["PATH", "HOME"];
+
+/// Converts the exit status of a command into a result.
+fn check_status(status: ExitStatus) -> Result<(), UpdateErr> {
+    status.code().map_or(Err(UpdateErr::Signal), |status| {
+        if status == 0 {
+            Ok(())
+        } else {
+            Err(UpdateErr::Status { status })
+        }
+    })
+}
+
+/// Waits for a command to exit, killing it if the update is cancelled in the
+/// meantime.
+fn wait(child: &mut Child, opts: &UpdateOptions) -> Result<ExitStatus, UpdateErr> {
+    if opts.cancel.is_none() {
+        return Ok(child.wait()?);
+    }
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if opts.cancelled() {
+            // The command may exit by itself in the meantime, which is fine.
+            let _ = child.kill();
+            child.wait()?;
+            return Err(UpdateErr::Cancelled);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// A 64-bit FNV-1a hasher.
+///
+/// Unlike `DefaultHasher`, its results are stable across Rust versions and
+/// platforms, and so can be stored or compared between runs.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    /// Writes a length-prefixed string, so that adjacent strings cannot be
+    /// confused with each other.
+    fn write_str(&mut self, s: &str) {
+        self.write(&(s.len() as u64).to_le_bytes());
+        self.write(s.as_bytes());
+    }
+
+    /// Writes a length-prefixed list of strings.
+    fn write_list<S: AsRef<str>>(&mut self, list: &[S]) {
+        self.write(&(list.len() as u64).to_le_bytes());
+        for s in list {
+            self.write_str(s.as_ref());
+        }
+    }
 }
 
 /// Creates a command from a string.
 ///
-/// The command will be wrappped in a platform-specific shell.
-fn string_to_command(command: &str) -> Command {
-    let mut cmd = Command::new(if cfg!(windows) { "cmd" } else { "sh" });
+/// The command will be wrappped in a platform-specific shell. The whole
+/// string, including any newlines, is passed to a single shell invocation.
+///
+/// If a niceness is given, the priority of the shell (and everything it runs)
+/// is lowered accordingly. See `Target::nice` for the platform mapping.
+fn string_to_command(command: &str, nice: Option<i32>) -> Command {
+    let shell = if cfg!(windows) { "cmd" } else { "sh" };
+    let mut cmd = match nice {
+        Some(nice) if cfg!(unix) => {
+            let mut cmd = Command::new("nice");
+            cmd.arg("-n").arg(nice.to_string()).arg(shell);
+            cmd
+        }
+        _ => Command::new(shell),
+    };
     cmd.arg(if cfg!(windows) { "/C" } else { "-c" });
     cmd.arg(command);
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+
+        const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x4000;
+        const IDLE_PRIORITY_CLASS: u32 = 0x40;
+        match nice {
+            Some(nice) if nice >= 15 => {
+                cmd.creation_flags(IDLE_PRIORITY_CLASS);
+            }
+            Some(nice) if nice > 0 => {
+                cmd.creation_flags(BELOW_NORMAL_PRIORITY_CLASS);
+            }
+            _ => {}
+        }
+    }
+
     cmd
 }
 
@@ -148,12 +833,32 @@ impl Target {
         Target {
             name,
             outputs: outputs.into_iter().map(|p| p.into()).collect(),
+            optional_outputs: Vec::new(),
+            freshness: Freshness::Any,
+            stamp: None,
+            volatile_inputs: Vec::new(),
             dependencies,
             commands,
+            parallel_commands: false,
+            on_failure: Vec::new(),
+            clean_env: false,
+            kept_env: Vec::new(),
+            nice: None,
+            stdin: None,
+            alias: None,
+            after: Vec::new(),
             extra,
         }
     }
 
+    /// Returns the extraneous data as the given concrete type.
+    ///
+    /// Returns `None` if the data is of a different type (i.e the target was
+    /// parsed from a different format).
+    pub fn extra_as<T: TargetExtra + 'static>(&self) -> Option<&T> {
+        (*self.extra).as_any().downcast_ref()
+    }
+
     /// Returns input files of the target, if known.
     ///
     /// Panics if the input files are unknown.
@@ -180,114 +885,888 @@ impl Target {
         }
     }
 
-    /// Updates the target.
+    /// Marks the target as up to date, without running its commands.
     ///
-    /// Returns `None` if it failed.
-    /// Otherwise, returns a boolean indicating whether an update was needed.
-    /// The commands are executed sequentially and synchronously.
+    /// This sets the modification time of each output to now, leaving
+    /// contents untouched. Missing outputs are created empty if `create` is
+    /// set, and are an error otherwise; missing optional outputs are skipped
+    /// either way. The stamp file, if any, is always touched.
     ///
-    /// Returns any errors that may have occurred during updating, including if
-    /// the commands failed to run.
-    pub fn update(&self, list: &HashMap<String, Target>) -> Result<bool, UpdateErr> {
-        // First, update dependencies, stopping on failure.
-        if self.dependencies().iter()
-            .try_fold(false, |res, dep| {
-                list.get(dep).unwrap().update(list).map(|r| res || r)
-            })?
-           // If a dependency was updated, force update.
-           // Otherwise, check modification times.
-        || self.inputs().iter() // TODO: Better error messages
-                .map(|p| fs::metadata(p).unwrap().modified().unwrap())
-                .max() // If no inputs, force update
-                .map_or(true, |latest| self.outputs.iter()
-                    .map(|o| fs::metadata(o).and_then(|md| md.modified()).ok())
-                    // If missing output, update
-                    // If output updated earlier than input, update
-                    .any(|o| o.map_or(true, |o| o < latest)))
-        {
-            // Update: Run all commands, printing exit status on failure of
-            // any.
-            self.commands
-                .iter()
-                .map(|cmd| string_to_command(&cmd))
-                .try_for_each(|mut cmd| {
-                    cmd.status()?
-                        .code()
-                        .map_or(Err(UpdateErr::Signal), |status| {
-                            if status == 0 {
-                                Ok(())
-                            } else {
-                                Err(UpdateErr::Status { status })
-                            }
-                        })
-                })?;
-            Ok(true)
-        } else {
-            Ok(false)
+    /// Any `StatCache` used before must forget the touched files.
+    pub fn touch_outputs(&self, create: bool) -> io::Result<()> {
+        for output in self.outputs.iter() {
+            touch(output, create)?;
+        }
+        for output in self.optional_outputs.iter().filter(|o| o.exists()) {
+            touch(output, false)?;
         }
+        if let Some(stamp) = &self.stamp {
+            touch(stamp, true)?;
+        }
+        Ok(())
     }
 
-    /// Finalizes a whole list of targets.
+    /// Returns why the outputs of the target are out of date with respect to
+    /// its inputs (as defined by its freshness mode), if they are.
     ///
-    /// Handles some external bookkeeping required by `finalize`.
-    pub fn finalize_list(mut list: Vec<Target>) -> HashMap<String, Target> {
-        let mut post = HashMap::with_capacity(list.len());
-        let mut path = Vec::new();
+    /// Missing outputs are always out of date, unless they are optional.
+    /// Missing inputs, however, are an error, unless they are ignored.
+    fn outdated(
+        &self,
+        opts: &UpdateOptions,
+        cache: &StatCache,
+    ) -> Result<Option<UpdateReason>, UpdateErr> {
+        // A missing output forces an update, so check for one before reading
+        // the (potentially many) inputs.
+        let missing = match &self.stamp {
+            Some(stamp) => Some(stamp).filter(|stamp| cache.modified(stamp).is_none()),
+            None => self.outputs.iter().find(|o| cache.modified(o).is_none()),
+        };
+        if let Some(path) = missing {
+            return Ok(Some(UpdateReason::MissingOutput(path.clone())));
+        }
 
-        // Loop over the targets. Keep popping, since we cannot iterate
-        // normally (because recursiveness may absorb multiple elements).
-        while let Some(elem) = list.pop() {
-            elem.finalize(&mut list, &mut post, &mut path);
+        // Volatile inputs are always newer than any output.
+        if let Some(name) = self.volatile_inputs.first() {
+            return Ok(Some(UpdateReason::Volatile(name.clone())));
         }
 
-        post
+        // TODO: Better error messages
+        let mut newest: Option<(&PathBuf, SystemTime)> = None;
+        for input in self.inputs().iter() {
+            let mtime = if opts.what_if.contains(input) {
+                SystemTime::now()
+            } else if opts.ignore_missing_inputs {
+                match cache.modified(input) {
+                    Some(mtime) => mtime,
+                    None => return Ok(Some(UpdateReason::MissingInput(input.clone()))),
+                }
+            } else {
+                cache.require(input).map_err(|source| UpdateErr::File {
+                    path: input.display().to_string(),
+                    source,
+                })?
+            };
+            if newest.is_none_or(|(_, latest)| mtime > latest) {
+                newest = Some((input, mtime));
+            }
+        }
+        // If no inputs, force update
+        let (input, latest) = match newest {
+            Some(newest) => newest,
+            None => return Ok(Some(UpdateReason::NoInputs)),
+        };
+        // Outputs within the tolerance count as up to date.
+        let latest = latest.checked_sub(opts.tolerance).unwrap_or(latest);
+        let newer = |output: &PathBuf| UpdateReason::NewerInput {
+            input: input.clone(),
+            output: output.clone(),
+        };
+
+        // A stamp file replaces the outputs entirely.
+        if let Some(stamp) = &self.stamp {
+            return Ok(Some(stamp)
+                .filter(|stamp| cache.modified(stamp).is_none_or(|s| s < latest))
+                .map(newer));
+        }
+
+        // All non-optional outputs exist at this point. Optional outputs are
+        // only compared if they exist.
+        let mut outputs = self
+            .outputs
+            .iter()
+            .chain(self.optional_outputs.iter())
+            .filter_map(|o| cache.modified(o).map(|mtime| (o, mtime)));
+        Ok(match self.freshness {
+            // If output updated earlier than input, update
+            Freshness::Any => outputs.find(|&(_, mtime)| mtime < latest),
+            // If newest output updated earlier than input, update
+            Freshness::All => outputs
+                .max_by_key(|&(_, mtime)| mtime)
+                .filter(|&(_, mtime)| mtime < latest),
+        }
+        .map(|(output, _)| newer(output)))
     }
 
-    /// Finalizes the target.
-    ///
-    /// Finalization involves verifying dependencies, differentiating inputs
-    /// from dependencies (if necessary), translating dependencies into primary
-    /// names for the referred-to targets, finalizing dependencies, and putting
-    /// the target into the given output hash map.
+    /// Describes whether the target and its (transitive) dependencies need
+    /// to be updated, and why, as an indented tree.
     ///
-    /// This function is recursive - it further finalizes all of its
-    /// dependencies. In order to prevent circular dependencies, which would
-    /// cause the application to hang, a "path" is taken, which describes which
-    /// targets called each other (in a stack-like list) until they reached
-    /// this call. If a dependency of the current function is found which
-    /// already exists on the path, then this function panics.
+    /// This makes the same decisions as `update` would, without running any
+    /// commands. Dependencies appearing multiple times are only described
+    /// in full the first time.
+    pub fn explain(
+        &self,
+        list: &BTreeMap<String, Target>,
+        opts: &UpdateOptions,
+        cache: &StatCache,
+    ) -> Result<String, UpdateErr> {
+        let mut out = String::new();
+        self.explain_with(list, opts, cache, 0, &mut HashMap::new(), &mut out)?;
+        Ok(out)
+    }
+
+    /// Describes the target at the given depth, given the targets already
+    /// described (and whether they need to be updated).
     ///
-    /// Additionally, this function panics if a dependency is not found or if a
-    /// target with the same primary name already exists in the output hashmap.
-    pub fn finalize(
-        mut self,
-        list: &mut Vec<Target>,
-        post: &mut HashMap<String, Target>,
-        path: &mut Vec<String>,
-    ) {
-        // First, we resolve (not finalize) dependencies.
-        let (inputs, dependencies) = self.dependencies.split(|dep| {
-            list.iter()
-                .chain(post.values())
-                .find(|tgt| tgt.extra.has_name(tgt, &dep))
-                .map(|target| {
-                    if target.name == dep {
-                        None
-                    } else {
-                        Some(target.name.clone())
-                    }
-                })
-        });
+    /// Returns whether the target needs to be updated.
+    fn explain_with(
+        &self,
+        list: &BTreeMap<String, Target>,
+        opts: &UpdateOptions,
+        cache: &StatCache,
+        depth: usize,
+        done: &mut HashMap<String, bool>,
+        out: &mut String,
+    ) -> Result<bool, UpdateErr> {
+        let indent = "  ".repeat(depth);
+        if let Some(&stale) = done.get(&self.name) {
+            let status = if stale { "needs update" } else { "up to date" };
+            out.push_str(&format!(
+                "{}{}: {} (see above)\n",
+                indent, self.name, status
+            ));
+            return Ok(stale);
+        }
 
-        // Then, we finalize each dependency, checking for cyclic or missing
-        // dependencies.
-        // Note that we push the name onto the path stack, and pop it off
-        // afterwards. This means that the path will be modified, but in the
-        // same state as how it was passed to the function.
-        path.push(self.name);
-        for dep in dependencies.iter() {
-            if path.contains(dep) {
-                panic!("Cyclic dependency found for {}!", dep);
+        let mut deps = String::new();
+        let (status, stale) = if opts.assume_old.contains(&self.name) {
+            (String::from("assumed up to date"), false)
+        } else {
+            // Mirror `update`: an updated dependency takes precedence.
+            let mut reason = None;
+            for dep in self.dependencies().iter() {
+                let stale =
+                    list[dep].explain_with(list, opts, cache, depth + 1, done, &mut deps)?;
+                if stale && reason.is_none() {
+                    reason = Some(UpdateReason::Dependency(dep.clone()));
+                }
+            }
+            let reason = match reason {
+                Some(reason) => Some(reason),
+                None => self.outdated(opts, cache)?,
+            };
+            match reason {
+                Some(reason) => (format!("needs update ({})", reason), true),
+                None => (String::from("up to date"), false),
+            }
+        };
+
+        out.push_str(&format!("{}{}: {}\n", indent, self.name, status));
+        out.push_str(&deps);
+        done.insert(self.name.clone(), stale);
+        Ok(stale)
+    }
+
+    /// Updates the target.
+    ///
+    /// Returns a boolean indicating whether an update was needed. The
+    /// commands of each target are run in order, one at a time, unless its
+    /// `parallel_commands` is set, in which case they are run concurrently.
+    ///
+    /// Returns any errors that may have occurred during updating, including if
+    /// the commands failed to run, if (enabled in the options) an output was
+    /// not produced by them, or if the update was cancelled through the
+    /// options.
+    ///
+    /// Modification times are read through the given cache, which should be
+    /// shared by every target updated in the same run.
+    ///
+    /// Each (transitive) dependency is updated at most once, even if multiple
+    /// targets depend upon it.
+    pub fn update(
+        &self,
+        list: &BTreeMap<String, Target>,
+        opts: &UpdateOptions,
+        cache: &StatCache,
+    ) -> Result<bool, UpdateErr> {
+        self.update_with(list, opts, cache, &mut HashMap::new())
+    }
+
+    /// Updates every root target (i.e every target no other target depends
+    /// upon), or every target at all if `every` is given.
+    ///
+    /// Targets shared between them are updated only once. Updating stops at
+    /// the first failure. Returns whether any target was updated.
+    pub fn update_all(
+        list: &BTreeMap<String, Target>,
+        every: bool,
+        opts: &UpdateOptions,
+        cache: &StatCache,
+    ) -> Result<bool, UpdateErr> {
+        let targets = if every {
+            list.values().collect()
+        } else {
+            Target::roots(list)
+        };
+
+        let mut done = HashMap::new();
+        targets.into_iter().try_fold(false, |res, tgt| {
+            tgt.update_with(list, opts, cache, &mut done)
+                .map(|r| res || r)
+        })
+    }
+
+    /// Returns the targets which no other target depends upon.
+    pub fn roots(list: &BTreeMap<String, Target>) -> Vec<&Target> {
+        list.values()
+            .filter(|tgt| {
+                !list
+                    .values()
+                    .any(|other| other.dependencies().contains(&tgt.name))
+            })
+            .collect()
+    }
+
+    /// Updates the target, given the targets already updated during the
+    /// current run (and whether they needed to be).
+    fn update_with(
+        &self,
+        list: &BTreeMap<String, Target>,
+        opts: &UpdateOptions,
+        cache: &StatCache,
+        done: &mut HashMap<String, bool>,
+    ) -> Result<bool, UpdateErr> {
+        if let Some(&updated) = done.get(&self.name) {
+            return Ok(updated);
+        }
+        if opts.cancelled() {
+            return Err(UpdateErr::Cancelled);
+        }
+
+        // Targets ordered before this one are updated first, but whether they
+        // needed to be does not matter.
+        if !opts.assume_old.contains(&self.name) {
+            for name in self.after.iter() {
+                list[name].update_with(list, opts, cache, done)?;
+            }
+        }
+
+        let updated = if opts.assume_old.contains(&self.name) {
+            opts.observer.on_skip(self);
+            false
+        } else if self.dependencies().iter()
+            // First, update dependencies, stopping on failure.
+            .try_fold(false, |res, dep| {
+                list[dep].update_with(list, opts, cache, done).map(|r| res || r)
+            })?
+           // If a dependency was updated, force update.
+           // Otherwise, check modification times.
+        || self.outdated(opts, cache)?.is_some()
+        {
+            opts.observer.on_target_start(self);
+            let res = self.rebuild(opts, cache);
+            opts.observer.on_target_finish(self, &res);
+            res?;
+            true
+        } else {
+            opts.observer.on_skip(self);
+            false
+        };
+
+        done.insert(self.name.clone(), updated);
+        Ok(updated)
+    }
+
+    /// Rebuilds the target, regardless of whether it is up to date.
+    ///
+    /// Dependencies are not updated.
+    fn rebuild(&self, opts: &UpdateOptions, cache: &StatCache) -> Result<(), UpdateErr> {
+        // Update: Run all commands, falling back to the fallback commands on
+        // failure of any.
+        self.run_commands(&self.commands, opts).or_else(|err| {
+            if self.on_failure.is_empty() || opts.cancelled() {
+                Err(err)
+            } else {
+                self.run_commands(&self.on_failure, opts)
+            }
+        })?;
+        cache.forget(self.outputs.iter().chain(self.optional_outputs.iter()));
+        if let Some(stamp) = &self.stamp {
+            touch(stamp, true).map_err(|source| UpdateErr::File {
+                path: stamp.display().to_string(),
+                source,
+            })?;
+            cache.forget(Some(stamp));
+        }
+
+        // Then, check that the outputs actually appeared.
+        if opts.verify_outputs {
+            if let Some(path) = self.outputs.iter().find(|o| !o.exists()) {
+                return Err(UpdateErr::MissingOutput {
+                    path: path.display().to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates a command from a string, with the environment set up as
+    /// configured for the target.
+    fn command(&self, command: &str) -> Command {
+        let mut cmd = string_to_command(command, self.nice);
+        if self.clean_env {
+            cmd.env_clear();
+            let kept = KEPT_ENV
+                .iter()
+                .cloned()
+                .chain(self.kept_env.iter().map(|v| v.as_str()));
+            for var in kept {
+                if let Some(val) = env::var_os(var) {
+                    cmd.env(var, val);
+                }
+            }
+        }
+        cmd
+    }
+
+    /// Starts a command of the target, feeding it standard input if set.
+    fn spawn(&self, command: &str) -> Result<Child, UpdateErr> {
+        let mut cmd = self.command(command);
+        match &self.stdin {
+            Some(Stdin::File(path)) => {
+                let file = fs::File::open(path).map_err(|source| UpdateErr::File {
+                    path: path.display().to_string(),
+                    source,
+                })?;
+                cmd.stdin(file);
+            }
+            Some(Stdin::Text(_)) => {
+                cmd.stdin(Stdio::piped());
+            }
+            None => {}
+        }
+
+        let mut child = cmd.spawn()?;
+        if let (Some(Stdin::Text(text)), Some(mut pipe)) = (&self.stdin, child.stdin.take()) {
+            // The text is written from another thread, since the command may
+            // not read it all before other commands are started. Dropping the
+            // pipe afterwards closes it, signalling the end of the input.
+            // Commands need not read all of it, so errors (e.g a broken pipe)
+            // are ignored.
+            let text = text.clone();
+            thread::spawn(move || {
+                let _ = pipe.write_all(text.as_bytes());
+            });
+        }
+        Ok(child)
+    }
+
+    /// Runs the given commands of the target, sequentially or concurrently.
+    ///
+    /// When running concurrently, every command is waited for even if one of
+    /// them fails, and the first error (in command order) is returned.
+    fn run_commands(&self, commands: &[String], opts: &UpdateOptions) -> Result<(), UpdateErr> {
+        if self.parallel_commands {
+            let children = commands
+                .iter()
+                .map(|cmd| {
+                    if opts.cancelled() {
+                        return Err(UpdateErr::Cancelled);
+                    }
+                    opts.observer.on_command(self, cmd);
+                    self.spawn(cmd)
+                })
+                .collect::<Vec<_>>();
+            let mut res = Ok(());
+            for child in children {
+                let status = child.and_then(|mut child| wait(&mut child, opts));
+                if let Err(err) = status.and_then(check_status) {
+                    if res.is_ok() {
+                        res = Err(err);
+                    }
+                }
+            }
+            res
+        } else {
+            commands.iter().try_for_each(|cmd| {
+                if opts.cancelled() {
+                    return Err(UpdateErr::Cancelled);
+                }
+                opts.observer.on_command(self, cmd);
+                check_status(wait(&mut self.spawn(cmd)?, opts)?)
+            })
+        }
+    }
+
+    /// Returns the order in which the target and its (transitive)
+    /// dependencies would be updated.
+    ///
+    /// Every target appears once, after all of its dependencies (and targets
+    /// it is ordered after). The target itself comes last.
+    pub fn build_order<'a>(&'a self, list: &'a BTreeMap<String, Target>) -> Vec<&'a Target> {
+        fn visit<'a>(
+            tgt: &'a Target,
+            list: &'a BTreeMap<String, Target>,
+            order: &mut Vec<&'a Target>,
+        ) {
+            if order.iter().any(|t| t.name == tgt.name) {
+                return;
+            }
+            for dep in tgt.after.iter().chain(tgt.dependencies().iter()) {
+                visit(list.get(dep).unwrap(), list, order);
+            }
+            order.push(tgt);
+        }
+
+        let mut order = Vec::new();
+        visit(self, list, &mut order);
+        order
+    }
+
+    /// Returns the primary names of all (transitive) dependencies of the
+    /// target.
+    ///
+    /// Every dependency appears once, after all of its own dependencies. The
+    /// target itself is not included, nor are targets it is only ordered
+    /// after.
+    pub fn dependencies_recursive<'a>(
+        &'a self,
+        list: &'a BTreeMap<String, Target>,
+    ) -> Vec<&'a str> {
+        fn visit<'a>(tgt: &'a Target, list: &'a BTreeMap<String, Target>, deps: &mut Vec<&'a str>) {
+            for dep in tgt.dependencies().iter() {
+                if !deps.contains(&dep.as_str()) {
+                    visit(&list[dep], list, deps);
+                    deps.push(dep);
+                }
+            }
+        }
+
+        let mut deps = Vec::new();
+        visit(self, list, &mut deps);
+        deps
+    }
+
+    /// Returns a standalone shell script which runs the commands of the
+    /// target and its dependencies, in build order.
+    ///
+    /// The script does not check whether anything is up to date; it runs
+    /// every command, stopping at the first failure (unless fallback commands
+    /// succeed instead). Stamp files are touched once the commands of their
+    /// target have run, and standard input is fed to each command as
+    /// configured. Commands are always run in order, even if they may be run
+    /// concurrently.
+    pub fn script(&self, list: &BTreeMap<String, Target>) -> String {
+        let mut script = String::from("#!/bin/sh\n");
+        script.push_str(&format!(
+            "# Generated by Samurai for target {}.\n",
+            self.name
+        ));
+        script.push_str("set -e\n");
+        for tgt in self.build_order(list) {
+            script.push_str(&format!("\n# {}\n", tgt.name));
+            if tgt.on_failure.is_empty() || tgt.commands.is_empty() {
+                for cmd in tgt.commands.iter() {
+                    script.push_str(&tgt.script_command(cmd));
+                    script.push('\n');
+                }
+            } else {
+                // A failing command within an AND-OR list does not stop the
+                // script, so the fallback is taken as with `update`.
+                script.push_str(&format!(
+                    "{{ {}; }} || {{ {}; }}\n",
+                    tgt.script_commands(&tgt.commands),
+                    tgt.script_commands(&tgt.on_failure)
+                ));
+            }
+            if let Some(stamp) = &tgt.stamp {
+                script.push_str(&format!("touch {}\n", quote(&stamp.to_string_lossy())));
+            }
+        }
+        script
+    }
+
+    /// Returns the given commands of the target as a single shell command,
+    /// which runs them in order, stopping at the first failure.
+    pub(crate) fn script_commands(&self, commands: &[String]) -> String {
+        commands
+            .iter()
+            .map(|cmd| match &self.stdin {
+                Some(_) => self.script_command(cmd),
+                None => format!("({})", cmd),
+            })
+            .collect::<Vec<_>>()
+            .join(" && ")
+    }
+
+    /// Returns a command of the target as a line of a shell script, with its
+    /// standard input redirected as configured.
+    fn script_command(&self, cmd: &str) -> String {
+        match &self.stdin {
+            Some(Stdin::File(path)) => format!("({}) < {}", cmd, quote(&path.to_string_lossy())),
+            Some(Stdin::Text(text)) => format!("printf '%s' {} | ({})", quote(text), cmd),
+            None => cmd.to_string(),
+        }
+    }
+
+    /// Removes the declared outputs (and stamp files) of the target and its
+    /// (transitive) dependencies.
+    ///
+    /// Targets which the target is only ordered after are not cleaned.
+    ///
+    /// Files which do not exist are skipped. Unless `force` is given, files
+    /// outside of the given root directory are not removed; if any are found,
+    /// an error is returned before anything is removed.
+    ///
+    /// Returns the files which were removed. Input files are never removed.
+    pub fn clean(
+        &self,
+        list: &BTreeMap<String, Target>,
+        root: &Path,
+        force: bool,
+    ) -> io::Result<Vec<PathBuf>> {
+        let root = root.canonicalize()?;
+        let files = self
+            .dependencies_recursive(list)
+            .into_iter()
+            .map(|name| &list[name])
+            .chain(Some(self))
+            .flat_map(|tgt| {
+                tgt.outputs
+                    .iter()
+                    .chain(tgt.optional_outputs.iter())
+                    .chain(tgt.stamp.iter())
+            })
+            .filter(|path| path.exists())
+            .collect::<Vec<_>>();
+
+        if !force {
+            for path in files.iter() {
+                if !path.canonicalize()?.starts_with(&root) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!(
+                            "Refusing to remove {} outside of {}",
+                            path.display(),
+                            root.display()
+                        ),
+                    ));
+                }
+            }
+        }
+
+        files
+            .into_iter()
+            .map(|path| fs::remove_file(path).map(|_| path.clone()))
+            .collect()
+    }
+
+    /// Returns a hash summarizing the full recipe of the target, including
+    /// its (transitive) dependencies.
+    ///
+    /// The hash covers names, commands (including fallback commands),
+    /// standard input, input paths (and volatile inputs), output paths
+    /// (including optional ones and stamp files), dependencies and the names of
+    /// targets ordered before, but not the contents of any files.
+    /// The recipes of targets which are only ordered before are not covered,
+    /// since they do not affect the outputs.
+    ///
+    /// It is independent of the order in which targets, inputs, outputs and
+    /// dependencies are listed, but not of the order of commands. It is stable
+    /// across runs, platforms and Rust versions.
+    pub fn manifest_hash(&self, list: &BTreeMap<String, Target>) -> u64 {
+        fn sorted<'a, I: Iterator<Item = &'a PathBuf>>(paths: I) -> Vec<String> {
+            let mut paths = paths
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        }
+
+        let mut targets = self
+            .dependencies_recursive(list)
+            .into_iter()
+            .map(|name| &list[name])
+            .chain(Some(self))
+            .collect::<Vec<_>>();
+        targets.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut hasher = Fnv::new();
+        for tgt in targets {
+            let mut deps = tgt.dependencies().clone();
+            deps.sort();
+            let mut after = tgt.after.clone();
+            after.sort();
+            let mut volatile = tgt.volatile_inputs.clone();
+            volatile.sort();
+            let stdin = match &tgt.stdin {
+                Some(Stdin::File(path)) => vec!["file".to_string(), path.display().to_string()],
+                Some(Stdin::Text(text)) => vec!["text".to_string(), text.clone()],
+                None => Vec::new(),
+            };
+
+            hasher.write_str(&tgt.name);
+            hasher.write_list(&sorted(tgt.inputs().iter()));
+            hasher.write_list(&volatile);
+            hasher.write_list(&sorted(tgt.outputs.iter()));
+            hasher.write_list(&sorted(tgt.optional_outputs.iter()));
+            hasher.write_list(&sorted(tgt.stamp.iter()));
+            hasher.write_list(&deps);
+            hasher.write_list(&after);
+            hasher.write_list(&tgt.commands);
+            hasher.write_list(&tgt.on_failure);
+            hasher.write_list(&stdin);
+        }
+        hasher.0
+    }
+
+    /// Returns the names of the targets producing the given file.
+    ///
+    /// Normally, at most one target produces a given file. More than one
+    /// producer is an error, which `finalize` checks for.
+    pub fn producers<'a>(list: &'a BTreeMap<String, Target>, path: &Path) -> Vec<&'a String> {
+        list.values()
+            .filter(|tgt| {
+                tgt.outputs
+                    .iter()
+                    .chain(tgt.optional_outputs.iter())
+                    .any(|o| o == path)
+            })
+            .map(|tgt| &tgt.name)
+            .collect()
+    }
+
+    /// Finds the target referred to by a requested name.
+    ///
+    /// The name is looked up as a primary name first, then as any name of a
+    /// target (using `TargetExtra::has_name`), and finally as a file produced
+    /// by a target. Since a finalized list has at most one producer for each
+    /// file, the result is never ambiguous.
+    ///
+    /// Returns `None` if no target matches.
+    pub fn lookup<'a>(list: &'a BTreeMap<String, Target>, name: &str) -> Option<&'a Target> {
+        list.get(name)
+            .or_else(|| list.values().find(|tgt| tgt.extra.has_name(tgt, name)))
+            .or_else(|| {
+                Target::producers(list, Path::new(name))
+                    .first()
+                    .map(|producer| &list[*producer])
+            })
+    }
+
+    /// Finds the targets referred to by a requested name, which may be a glob
+    /// pattern.
+    ///
+    /// The name is first looked up as with `lookup`, since names may contain
+    /// any character. If no target is found and the name contains any of the
+    /// wildcards `*`, `?` or `[`, it is matched against the primary names of
+    /// all targets instead, and the matching targets are returned in order of
+    /// name.
+    ///
+    /// Returns an error if no target matches, or if the pattern is invalid
+    /// (e.g contains the character range `[z-a]`).
+    pub fn lookup_all<'a>(
+        list: &'a BTreeMap<String, Target>,
+        name: &str,
+    ) -> Result<Vec<&'a Target>, LookupErr> {
+        if let Some(tgt) = Target::lookup(list, name) {
+            return Ok(vec![tgt]);
+        }
+
+        let found = if name.contains(['*', '?', '[']) {
+            let pattern = glob_to_regex(name).map_err(|source| LookupErr::Pattern { source })?;
+            list.values()
+                .filter(|tgt| pattern.is_match(&tgt.name))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        if found.is_empty() {
+            Err(LookupErr::NoMatch {
+                name: name.to_string(),
+            })
+        } else {
+            Ok(found)
+        }
+    }
+
+    /// Returns the names of the targets affected by a change to the given
+    /// file.
+    ///
+    /// A target is affected if it takes the file as an input, if it depends
+    /// (transitively) upon an affected target, or if it takes an output of an
+    /// affected target as an input. The names are sorted.
+    ///
+    /// Like `inputs` and `dependencies`, this expects a finalized list.
+    pub fn affected_by<'a>(list: &'a BTreeMap<String, Target>, path: &Path) -> Vec<&'a String> {
+        let mut affected: Vec<&Target> = list
+            .values()
+            .filter(|tgt| tgt.inputs().iter().any(|i| i == path))
+            .collect();
+
+        // Keep adding dependers and consumers of affected targets until
+        // nothing changes.
+        let mut next = 0;
+        while next < affected.len() {
+            let cause = affected[next];
+            let outputs = cause
+                .outputs
+                .iter()
+                .chain(cause.optional_outputs.iter())
+                .collect::<Vec<_>>();
+            for tgt in list.values() {
+                let consumes = tgt.inputs().iter().any(|i| outputs.contains(&i));
+                if (consumes || tgt.dependencies().contains(&cause.name))
+                    && !affected.iter().any(|a| a.name == tgt.name)
+                {
+                    affected.push(tgt);
+                }
+            }
+            next += 1;
+        }
+
+        let mut affected = affected
+            .into_iter()
+            .map(|tgt| &tgt.name)
+            .collect::<Vec<_>>();
+        affected.sort();
+        affected
+    }
+
+    /// Resolves the paths of finalized targets against a source and a build
+    /// root, for out-of-tree builds.
+    ///
+    /// Relative outputs, optional outputs and stamp files are placed under
+    /// `build_root`. Relative inputs (and files fed to standard input) are
+    /// placed under `src_root`, unless they are (before resolution) an output
+    /// or stamp file of some target, in which case they are generated and so
+    /// placed under `build_root` too. Absolute paths are left as-is.
+    ///
+    /// This should be called once, directly after finalization.
+    pub fn relocate(list: &mut BTreeMap<String, Target>, src_root: &Path, build_root: &Path) {
+        let generated = list
+            .values()
+            .flat_map(|tgt| {
+                tgt.outputs
+                    .iter()
+                    .chain(tgt.optional_outputs.iter())
+                    .chain(tgt.stamp.iter())
+            })
+            .cloned()
+            .collect::<HashSet<_>>();
+        let source = |path: &PathBuf| {
+            let root = if generated.contains(path) {
+                build_root
+            } else {
+                src_root
+            };
+            root.join(path)
+        };
+
+        for tgt in list.values_mut() {
+            if let MixedDeps::UnMixed { inputs, .. } = &mut tgt.dependencies {
+                for input in inputs.iter_mut() {
+                    *input = source(input);
+                }
+            } else {
+                panic!("Dependencies are still mixed!");
+            }
+            if let Some(Stdin::File(path)) = &mut tgt.stdin {
+                *path = source(path);
+            }
+
+            let outputs = tgt
+                .outputs
+                .iter_mut()
+                .chain(tgt.optional_outputs.iter_mut())
+                .chain(tgt.stamp.iter_mut());
+            for output in outputs {
+                *output = build_root.join(&output);
+            }
+        }
+    }
+
+    /// Finalizes a whole list of targets.
+    ///
+    /// Handles some external bookkeeping required by `finalize`.
+    ///
+    /// The resulting map is ordered by primary name, so that iterating over it
+    /// (e.g when listing or reporting targets) is deterministic.
+    pub fn finalize_list(mut list: Vec<Target>) -> BTreeMap<String, Target> {
+        let mut post = BTreeMap::new();
+        let mut path = Vec::new();
+
+        // Loop over the targets. Keep popping, since we cannot iterate
+        // normally (because recursiveness may absorb multiple elements).
+        while let Some(elem) = list.pop() {
+            elem.finalize(&mut list, &mut post, &mut path);
+        }
+
+        post
+    }
+
+    /// Finalizes the target.
+    ///
+    /// Finalization involves verifying dependencies, differentiating inputs
+    /// from dependencies (if necessary), translating dependencies into primary
+    /// names for the referred-to targets, finalizing dependencies, and putting
+    /// the target into the given output map.
+    ///
+    /// This function is recursive - it further finalizes all of its
+    /// dependencies. In order to prevent circular dependencies, which would
+    /// cause the application to hang, a "path" is taken, which describes which
+    /// targets called each other (in a stack-like list) until they reached
+    /// this call. If a dependency of the current function is found which
+    /// already exists on the path, then this function panics.
+    ///
+    /// Additionally, this function panics if a dependency (or the target this
+    /// target is an alias for) is not found, if a target with the same primary
+    /// name already exists in the output map, or if a target in the output map
+    /// already produces one of the outputs of this target.
+    pub fn finalize(
+        mut self,
+        list: &mut Vec<Target>,
+        post: &mut BTreeMap<String, Target>,
+        path: &mut Vec<String>,
+    ) {
+        // First, we resolve (not finalize) dependencies.
+        let (inputs, mut dependencies) =
+            resolve_dependencies(self.dependencies, list.iter().chain(post.values()));
+
+        // An alias depends on its referent, so that it is finalized (and
+        // checked for cycles) like any other dependency.
+        if let Some(alias) = &self.alias {
+            let name = &self.name;
+            let referent = list
+                .iter()
+                .chain(post.values())
+                .find(|tgt| tgt.extra.has_name(tgt, alias))
+                .map(|tgt| tgt.name.clone())
+                .unwrap_or_else(|| {
+                    // Targets being finalized are in neither list.
+                    if name == alias || path.contains(alias) {
+                        panic!("Cyclic dependency found for {}!", alias);
+                    }
+                    panic!("Alias {} refers to missing target {}!", name, alias)
+                });
+            if !dependencies.contains(&referent) {
+                dependencies.push(referent);
+            }
+        }
+
+        // Targets to be updated before this one are resolved by name, just
+        // like dependencies.
+        self.after = self
+            .after
+            .iter()
+            .map(|name| {
+                list.iter()
+                    .chain(post.values())
+                    .find(|tgt| tgt.extra.has_name(tgt, name))
+                    .map(|tgt| tgt.name.clone())
+                    .unwrap_or_else(|| panic!("Ordering dependency {} not found!", name))
+            })
+            .collect();
+
+        // Then, we finalize each dependency (and target to be updated
+        // before this one), checking for cyclic or missing dependencies.
+        // Note that we push the name onto the path stack, and pop it off
+        // afterwards. This means that the path will be modified, but in the
+        // same state as how it was passed to the function.
+        path.push(self.name);
+        for dep in dependencies.iter().chain(self.after.iter()) {
+            if path.contains(dep) {
+                panic!("Cyclic dependency found for {}!", dep);
             }
 
             // Now, we check to see if we have to finalize the dependency.
@@ -298,17 +1777,27 @@ impl Target {
 
             // Note that all dependencies exist, since the `MixedDeps::split`
             // function checked it for all dependencies. As such, any
-            // dependencies not in `list` are in the output hash map already.
+            // dependencies not in `list` are in the output map already.
         }
         self.name = path.pop().unwrap();
 
-        // Now, the target is stored on the output hash map.
+        // Now, the target is stored on the output map.
         // NOTE: At the moment, the key is cloned from the name. If possible,
         // this should be prevented.
         self.dependencies = MixedDeps::UnMixed {
             inputs,
             dependencies,
         };
+        for output in self.outputs.iter().chain(self.optional_outputs.iter()) {
+            if let Some(other) = Target::producers(post, output).first() {
+                panic!(
+                    "Output {} is produced by both {} and {}!",
+                    output.display(),
+                    other,
+                    self.name
+                );
+            }
+        }
         if let Some(tgt) = post.insert(self.name.clone(), self) {
             // Duplicate found! Panic.
             panic!("Duplicate target {} found!", tgt.name);
@@ -316,3 +1805,1142 @@ impl Target {
         }
     }
 }
+
+/// Helpers for creating targets in tests.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::{MixedDeps, Target, TargetExtra};
+
+    /// Extraneous data for targets created by tests.
+    #[derive(Debug)]
+    pub(crate) struct Extra;
+
+    impl TargetExtra for Extra {}
+
+    /// Creates an unfinalized target with mixed dependencies.
+    pub(crate) fn target(name: &str, outputs: &[&str], deps: &[&str], commands: &[&str]) -> Target {
+        Target::new(
+            name.to_string(),
+            outputs.iter().map(|o| o.to_string()).collect(),
+            MixedDeps::Mixed(deps.iter().map(|d| d.to_string()).collect()),
+            commands.iter().map(|c| c.to_string()).collect(),
+            Box::new(Extra),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::target;
+    use super::*;
+
+    /// Creates an empty directory unique to the given test.
+    fn tempdir(test: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("samurai-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Creates an empty file, last modified the given time ago.
+    fn file(path: &Path, age: Duration) {
+        fs::write(path, "").unwrap();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::now() - age)
+            .unwrap();
+    }
+
+    /// Returns a command which creates the first file and waits (for up to
+    /// five seconds) for the second to exist, succeeding only if it does.
+    ///
+    /// Two such commands waiting for each other only succeed when run
+    /// concurrently.
+    fn rendezvous(mine: &str, theirs: &str) -> String {
+        format!(
+            concat!(
+                "touch '{}'; i=0; ",
+                "while [ ! -e '{}' ] && [ $i -lt 500 ]; do sleep 0.01; i=$((i+1)); done; ",
+                "[ -e '{1}' ]",
+            ),
+            mine, theirs
+        )
+    }
+
+    /// Returns a path within the given directory, as a string.
+    fn within(dir: &Path, name: &str) -> String {
+        dir.join(name).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn debug_finalized() {
+        let list = Target::finalize_list(vec![
+            target("a", &["a.out"], &["b", "a.in"], &["cc a.in"]),
+            target("b", &[], &[], &[]),
+        ]);
+        let debug = format!("{:?}", list["a"]);
+        assert!(debug.contains("name: \"a\""));
+        assert!(debug.contains("UnMixed"));
+        assert!(debug.contains("\"a.in\""));
+        assert!(debug.contains("extra: Extra"));
+    }
+
+    #[test]
+    fn producers() {
+        let mut list = Target::finalize_list(vec![
+            target("a", &["a.out"], &[], &[]),
+            target("b", &["b.out"], &[], &[]),
+        ]);
+        assert!(Target::producers(&list, Path::new("c.out")).is_empty());
+        assert_eq!(Target::producers(&list, Path::new("a.out")), vec!["a"]);
+
+        // A finalized list never has two producers, so build one by hand.
+        list.get_mut("b")
+            .unwrap()
+            .optional_outputs
+            .push("a.out".into());
+        assert_eq!(Target::producers(&list, Path::new("a.out")), vec!["a", "b"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "is produced by both")]
+    fn finalize_multiple_producers() {
+        Target::finalize_list(vec![
+            target("a", &["out"], &[], &[]),
+            target("b", &["out"], &[], &[]),
+        ]);
+    }
+
+    #[test]
+    fn affected_by_shared_header() {
+        let list = Target::finalize_list(vec![
+            target("a", &["a.o"], &["a.c", "common.h"], &[]),
+            target("b", &["b.o"], &["b.c", "common.h"], &[]),
+            target("c", &["c.o"], &["c.c"], &[]),
+            // Object files are plain inputs here, not dependencies.
+            target("app", &["app"], &["a.o", "b.o"], &[]),
+            target("all", &[], &["app"], &[]),
+        ]);
+        assert_eq!(
+            list["app"].inputs(),
+            &vec![PathBuf::from("a.o"), PathBuf::from("b.o")]
+        );
+
+        assert_eq!(
+            Target::affected_by(&list, Path::new("common.h")),
+            vec!["a", "all", "app", "b"]
+        );
+        assert_eq!(Target::affected_by(&list, Path::new("c.c")), vec!["c"]);
+        assert!(Target::affected_by(&list, Path::new("other.h")).is_empty());
+    }
+
+    #[test]
+    fn freshness_any_and_all() {
+        let dir = tempdir("freshness");
+        let (input, old, new) = (within(&dir, "in"), within(&dir, "old"), within(&dir, "new"));
+        file(Path::new(&input), Duration::from_secs(50));
+        file(Path::new(&old), Duration::from_secs(100));
+        file(Path::new(&new), Duration::from_secs(10));
+
+        let mut list = Target::finalize_list(vec![target("a", &[&old, &new], &[&input], &[])]);
+        let opts = UpdateOptions::default();
+        match list["a"].outdated(&opts, &StatCache::new()).unwrap() {
+            Some(UpdateReason::NewerInput { output, .. }) => assert_eq!(output, Path::new(&old)),
+            reason => panic!("unexpected reason {:?}", reason),
+        }
+
+        // Only the newest output is compared.
+        list.get_mut("a").unwrap().freshness = Freshness::All;
+        assert!(list["a"]
+            .outdated(&opts, &StatCache::new())
+            .unwrap()
+            .is_none());
+
+        // Unless all outputs are older.
+        file(Path::new(&new), Duration::from_secs(60));
+        assert!(list["a"]
+            .outdated(&opts, &StatCache::new())
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn stamp_governs_rebuilds() {
+        let dir = tempdir("stamp");
+        let (input, output) = (within(&dir, "in"), within(&dir, "out"));
+        let stamp = within(&dir, "stamp");
+        file(Path::new(&input), Duration::from_secs(50));
+        file(Path::new(&output), Duration::from_secs(100));
+
+        let mut a = target("a", &[&output], &[&input], &[]);
+        a.stamp = Some(stamp.clone().into());
+        let list = Target::finalize_list(vec![a]);
+        let opts = UpdateOptions::default();
+        match list["a"].outdated(&opts, &StatCache::new()).unwrap() {
+            Some(UpdateReason::MissingOutput(path)) => assert_eq!(path, Path::new(&stamp)),
+            reason => panic!("unexpected reason {:?}", reason),
+        }
+
+        // The stamp is newer than the input, so the old output is ignored.
+        file(Path::new(&stamp), Duration::from_secs(10));
+        assert!(list["a"]
+            .outdated(&opts, &StatCache::new())
+            .unwrap()
+            .is_none());
+
+        file(Path::new(&stamp), Duration::from_secs(60));
+        match list["a"].outdated(&opts, &StatCache::new()).unwrap() {
+            Some(UpdateReason::NewerInput { output, .. }) => assert_eq!(output, Path::new(&stamp)),
+            reason => panic!("unexpected reason {:?}", reason),
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn assume_old_skips_commands() {
+        let dir = tempdir("assume-old");
+        let marker = within(&dir, "marker");
+        let list = Target::finalize_list(vec![target(
+            "a",
+            &[],
+            &[],
+            &[&format!("touch '{}'", marker)],
+        )]);
+
+        let mut opts = UpdateOptions::default();
+        opts.assume_old.push("a".to_string());
+        assert!(!list["a"].update(&list, &opts, &StatCache::new()).unwrap());
+        assert!(!Path::new(&marker).exists());
+
+        // Without it, a target without inputs always runs.
+        let opts = UpdateOptions::default();
+        assert!(list["a"].update(&list, &opts, &StatCache::new()).unwrap());
+        assert!(Path::new(&marker).exists());
+    }
+
+    #[test]
+    fn what_if_makes_downstream_stale() {
+        let dir = tempdir("what-if");
+        let (source, object) = (within(&dir, "a.c"), within(&dir, "a.o"));
+        let (input, output) = (within(&dir, "b.in"), within(&dir, "b"));
+        file(Path::new(&source), Duration::from_secs(100));
+        file(Path::new(&object), Duration::from_secs(50));
+        file(Path::new(&input), Duration::from_secs(100));
+        file(Path::new(&output), Duration::from_secs(50));
+
+        let list = Target::finalize_list(vec![
+            target("a", &[&object], &[&source], &[]),
+            target("b", &[&output], &["a", &input], &[]),
+        ]);
+        let mut opts = UpdateOptions::default();
+        let explanation = list["b"].explain(&list, &opts, &StatCache::new()).unwrap();
+        assert_eq!(explanation, "b: up to date\n  a: up to date\n");
+
+        opts.what_if.push(source.into());
+        let explanation = list["b"].explain(&list, &opts, &StatCache::new()).unwrap();
+        assert!(explanation.starts_with("b: needs update (dependency a needs update)\n"));
+        assert!(explanation.contains("  a: needs update (input "));
+    }
+
+    #[test]
+    fn tolerance() {
+        let dir = tempdir("tolerance");
+        let (input, output) = (within(&dir, "in"), within(&dir, "out"));
+        file(Path::new(&input), Duration::from_millis(10_000));
+        file(Path::new(&output), Duration::from_millis(10_300));
+
+        let list = Target::finalize_list(vec![target("a", &[&output], &[&input], &[])]);
+        let mut opts = UpdateOptions::default();
+        assert!(list["a"]
+            .outdated(&opts, &StatCache::new())
+            .unwrap()
+            .is_some());
+
+        opts.tolerance = Duration::from_millis(500);
+        assert!(list["a"]
+            .outdated(&opts, &StatCache::new())
+            .unwrap()
+            .is_none());
+
+        opts.tolerance = Duration::from_millis(200);
+        assert!(list["a"]
+            .outdated(&opts, &StatCache::new())
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn touch_outputs() {
+        let dir = tempdir("touch");
+        let (input, output) = (within(&dir, "in"), within(&dir, "out"));
+        let missing = within(&dir, "missing");
+        file(Path::new(&input), Duration::from_secs(50));
+        file(Path::new(&output), Duration::from_secs(100));
+
+        let list = Target::finalize_list(vec![
+            target("a", &[&output], &[&input], &[]),
+            target("b", &[&missing], &[&input], &[]),
+        ]);
+        let opts = UpdateOptions::default();
+        assert!(list["a"]
+            .outdated(&opts, &StatCache::new())
+            .unwrap()
+            .is_some());
+        list["a"].touch_outputs(false).unwrap();
+        assert!(list["a"]
+            .outdated(&opts, &StatCache::new())
+            .unwrap()
+            .is_none());
+
+        // Missing outputs are only created if requested.
+        assert!(list["b"].touch_outputs(false).is_err());
+        assert!(!Path::new(&missing).exists());
+        list["b"].touch_outputs(true).unwrap();
+        assert!(list["b"]
+            .outdated(&opts, &StatCache::new())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn parallel_commands() {
+        let dir = tempdir("parallel");
+        let (x, y) = (within(&dir, "x"), within(&dir, "y"));
+        let mut a = target("a", &[], &[], &[&rendezvous(&x, &y), &rendezvous(&y, &x)]);
+        a.parallel_commands = true;
+        let list = Target::finalize_list(vec![a]);
+        assert!(list["a"]
+            .update(&list, &UpdateOptions::default(), &StatCache::new())
+            .unwrap());
+    }
+
+    #[test]
+    fn finalize_list_ordered_by_name() {
+        let names = |list: Vec<Target>| {
+            Target::finalize_list(list)
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        let forwards = names(vec![
+            target("c", &[], &["a"], &[]),
+            target("a", &[], &[], &[]),
+            target("b", &[], &["c"], &[]),
+        ]);
+        let backwards = names(vec![
+            target("b", &[], &["c"], &[]),
+            target("a", &[], &[], &[]),
+            target("c", &[], &["a"], &[]),
+        ]);
+        assert_eq!(forwards, vec!["a", "b", "c"]);
+        assert_eq!(forwards, backwards);
+    }
+
+    #[test]
+    fn manifest_hash_order_independent() {
+        let hash = |list: Vec<Target>| {
+            let list = Target::finalize_list(list);
+            list["a"].manifest_hash(&list)
+        };
+        let original = hash(vec![
+            target(
+                "a",
+                &["a.out", "a.log"],
+                &["b", "c", "a.in", "a.h"],
+                &["cc", "ld"],
+            ),
+            target("b", &["b.out"], &["b.in"], &["cc"]),
+            target("c", &[], &[], &[]),
+        ]);
+        let reordered = hash(vec![
+            target("c", &[], &[], &[]),
+            target("b", &["b.out"], &["b.in"], &["cc"]),
+            target(
+                "a",
+                &["a.log", "a.out"],
+                &["a.h", "c", "a.in", "b"],
+                &["cc", "ld"],
+            ),
+        ]);
+        let changed = hash(vec![
+            target(
+                "a",
+                &["a.out", "a.log"],
+                &["b", "c", "a.in", "a.h"],
+                &["cc", "ld"],
+            ),
+            target("b", &["b.out"], &["b.in"], &["cc -O2"]),
+            target("c", &[], &[], &[]),
+        ]);
+        assert_eq!(original, reordered);
+        assert_ne!(original, changed);
+    }
+
+    #[test]
+    fn retry_transient_errors() {
+        let failing = |failures: u32, kind: io::ErrorKind| {
+            let mut calls = 0;
+            move || {
+                calls += 1;
+                if calls <= failures {
+                    Err(io::Error::new(kind, "failed"))
+                } else {
+                    Ok(calls)
+                }
+            }
+        };
+
+        assert_eq!(retry(3, failing(2, io::ErrorKind::Interrupted)).unwrap(), 3);
+        let err = retry(3, failing(4, io::ErrorKind::Interrupted)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        // Other errors are never retried.
+        let err = retry(3, failing(1, io::ErrorKind::NotFound)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn error_codes() {
+        let io = || io::Error::other("failed");
+        let errors = [
+            (UpdateErr::Io { source: io() }, 1, ErrorCategory::Io),
+            (UpdateErr::Status { status: 2 }, 2, ErrorCategory::Exec),
+            (UpdateErr::Signal, 3, ErrorCategory::Exec),
+            (
+                UpdateErr::MissingOutput {
+                    path: "out".to_string(),
+                },
+                4,
+                ErrorCategory::Exec,
+            ),
+            (
+                UpdateErr::File {
+                    path: "in".to_string(),
+                    source: io(),
+                },
+                5,
+                ErrorCategory::Io,
+            ),
+            (UpdateErr::Cancelled, 6, ErrorCategory::Cancelled),
+        ];
+        for (err, code, category) in errors.iter() {
+            assert_eq!(err.code(), *code);
+            assert_eq!(err.category(), *category);
+        }
+
+        let mut codes = errors
+            .iter()
+            .map(|(err, _, _)| err.code())
+            .collect::<Vec<_>>();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn after_orders_without_freshness() {
+        let dir = tempdir("after");
+        let log = within(&dir, "log");
+        let (input, output) = (within(&dir, "in"), within(&dir, "out"));
+        file(Path::new(&input), Duration::from_secs(100));
+        file(Path::new(&output), Duration::from_secs(50));
+
+        let append = |name: &str| format!("echo {} >> '{}'", name, log);
+        let mut seed = target("seed", &[&output], &[&input], &[&append("seed")]);
+        seed.after = vec!["migrate".to_string()];
+        let mut migrate = target("migrate", &[], &[], &[&append("migrate")]);
+        migrate.stamp = Some(within(&dir, "migrate.stamp").into());
+        let list = Target::finalize_list(vec![seed, migrate]);
+        assert!(list["seed"].dependencies().is_empty());
+        let order = list["seed"].build_order(&list);
+        let order = order.iter().map(|t| t.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(order, vec!["migrate", "seed"]);
+
+        // The ordered target always runs (having no inputs), but does not make
+        // the up-to-date target run.
+        let opts = UpdateOptions::default();
+        assert!(!list["seed"]
+            .update(&list, &opts, &StatCache::new())
+            .unwrap());
+        assert_eq!(fs::read_to_string(&log).unwrap(), "migrate\n");
+
+        file(Path::new(&input), Duration::from_secs(10));
+        assert!(list["seed"]
+            .update(&list, &opts, &StatCache::new())
+            .unwrap());
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            "migrate\nmigrate\nseed\n"
+        );
+
+        // Only the target itself is cleaned.
+        let removed = list["seed"].clean(&list, &dir, false).unwrap();
+        assert_eq!(removed, vec![PathBuf::from(&output)]);
+        assert!(dir.join("migrate.stamp").exists());
+    }
+
+    #[test]
+    fn dependencies_recursive_diamond() {
+        let list = Target::finalize_list(vec![
+            target("top", &[], &["left", "right"], &[]),
+            target("left", &[], &["bottom"], &[]),
+            target("right", &[], &["bottom"], &[]),
+            target("bottom", &[], &[], &[]),
+        ]);
+        assert_eq!(
+            list["top"].dependencies_recursive(&list),
+            vec!["bottom", "left", "right"]
+        );
+        assert_eq!(list["left"].dependencies_recursive(&list), vec!["bottom"]);
+        assert!(list["bottom"].dependencies_recursive(&list).is_empty());
+    }
+
+    #[test]
+    fn script_touches_stamp() {
+        let mut a = target("a", &[], &["b"], &["echo a"]);
+        a.stamp = Some("it's.stamp".into());
+        let list = Target::finalize_list(vec![a, target("b", &[], &[], &["echo b"])]);
+        assert_eq!(
+            list["a"].script(&list),
+            "#!/bin/sh\n# Generated by Samurai for target a.\nset -e\n\
+             \n# b\necho b\n\
+             \n# a\necho a\ntouch 'it'\\''s.stamp'\n"
+        );
+    }
+
+    #[test]
+    fn stdin_in_script_and_hash() {
+        let with_stdin = |stdin: Option<Stdin>| {
+            let mut a = target("a", &[], &[], &["cat"]);
+            a.stdin = stdin;
+            Target::finalize_list(vec![a])
+        };
+
+        let file = with_stdin(Some(Stdin::File("in put".into())));
+        assert!(file["a"].script(&file).ends_with("\n(cat) < 'in put'\n"));
+        let text = with_stdin(Some(Stdin::Text("it's\n".to_string())));
+        assert!(text["a"]
+            .script(&text)
+            .ends_with("\nprintf '%s' 'it'\\''s\n' | (cat)\n"));
+        let none = with_stdin(None);
+        assert!(none["a"].script(&none).ends_with("\ncat\n"));
+
+        let hashes = [
+            file["a"].manifest_hash(&file),
+            text["a"].manifest_hash(&text),
+            none["a"].manifest_hash(&none),
+        ];
+        assert_ne!(hashes[0], hashes[1]);
+        assert_ne!(hashes[0], hashes[2]);
+        assert_ne!(hashes[1], hashes[2]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn fallback_commands() {
+        let dir = tempdir("fallback");
+        let output = within(&dir, "out");
+        let mut a = target(
+            "a",
+            &[&output],
+            &[],
+            &["false", &format!("echo fast > '{}'", output)],
+        );
+        a.on_failure = vec![format!("echo slow > '{}'", output)];
+        let list = Target::finalize_list(vec![a]);
+
+        // The fast path fails, but the slow one succeeds.
+        assert!(list["a"]
+            .update(&list, &UpdateOptions::default(), &StatCache::new())
+            .unwrap());
+        assert_eq!(fs::read_to_string(&output).unwrap(), "slow\n");
+
+        // The generated script takes the same path.
+        fs::remove_file(&output).unwrap();
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(list["a"].script(&list))
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(fs::read_to_string(&output).unwrap(), "slow\n");
+
+        // Without a fallback, the failure is reported.
+        let b = target("b", &[], &[], &["false"]);
+        let list = Target::finalize_list(vec![b]);
+        match list["b"].update(&list, &UpdateOptions::default(), &StatCache::new()) {
+            Err(UpdateErr::Status { status: 1 }) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn fallback_commands_in_hash() {
+        let with_fallback = |fallback: &[&str]| {
+            let mut a = target("a", &[], &[], &["fast"]);
+            a.on_failure = fallback.iter().map(|c| c.to_string()).collect();
+            let list = Target::finalize_list(vec![a]);
+            list["a"].manifest_hash(&list)
+        };
+        assert_ne!(with_fallback(&[]), with_fallback(&["slow"]));
+        assert_ne!(with_fallback(&["slow"]), with_fallback(&["slower"]));
+    }
+
+    #[test]
+    fn missing_stdin_file() {
+        let dir = tempdir("stdin-file");
+        let missing = within(&dir, "missing");
+        let mut a = target("a", &[], &[], &["cat"]);
+        a.stdin = Some(Stdin::File(missing.clone().into()));
+        let list = Target::finalize_list(vec![a]);
+
+        let err = list["a"]
+            .update(&list, &UpdateOptions::default(), &StatCache::new())
+            .unwrap_err();
+        match &err {
+            UpdateErr::File { path, .. } => assert_eq!(path, &missing),
+            err => panic!("unexpected error {:?}", err),
+        }
+        assert_eq!(err.to_string(), format!("I/O Error on {}", missing));
+    }
+
+    #[test]
+    fn lookup_all_globs() {
+        let list = Target::finalize_list(vec![
+            target("test-unit", &[], &[], &[]),
+            target("test-integration", &[], &[], &[]),
+            target("test.x", &[], &[], &[]),
+            target("build", &["out.bin"], &[], &[]),
+            target("data0", &[], &[], &[]),
+            target("data[0]", &[], &[], &[]),
+        ]);
+        let names = |name: &str| {
+            Target::lookup_all(&list, name)
+                .unwrap()
+                .iter()
+                .map(|tgt| tgt.name.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names("test-*"), vec!["test-integration", "test-unit"]);
+        assert_eq!(names("test?[!u]*"), vec!["test-integration", "test.x"]);
+        assert_eq!(names("[a-c]uil?"), vec!["build"]);
+        // Names without wildcards are looked up as usual.
+        assert_eq!(names("out.bin"), vec!["build"]);
+        // Special regex characters are taken literally.
+        assert!(names("test.*").contains(&"test.x"));
+        assert!(!names("test.*").contains(&"test-unit"));
+        // Names with wildcards may still refer to a target exactly.
+        assert_eq!(names("data[0]"), vec!["data[0]"]);
+        assert_eq!(names("data*"), vec!["data0", "data[0]"]);
+
+        match Target::lookup_all(&list, "deploy-*") {
+            Err(LookupErr::NoMatch { name }) => assert_eq!(name, "deploy-*"),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(Target::lookup_all(&list, "deploy").is_err());
+        match Target::lookup_all(&list, "[z-a]*") {
+            Err(LookupErr::Pattern { .. }) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn relocate_roots() {
+        let mut a = target("a", &["a.o"], &["a.c", "/abs/a.h"], &[]);
+        a.optional_outputs = vec!["a.map".into()];
+        a.stamp = Some("a.stamp".into());
+        let mut b = target("b", &["/abs/b"], &["a.o", "a.map", "a.stamp", "b.c"], &[]);
+        b.stdin = Some(Stdin::File("b.in".into()));
+        let mut list = Target::finalize_list(vec![a, b]);
+        Target::relocate(&mut list, Path::new("/src"), Path::new("/build"));
+        let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+        assert_eq!(list["a"].inputs(), &paths(&["/src/a.c", "/abs/a.h"]));
+        assert_eq!(list["a"].outputs, paths(&["/build/a.o"]));
+        assert_eq!(list["a"].optional_outputs, paths(&["/build/a.map"]));
+        assert_eq!(list["a"].stamp, Some(PathBuf::from("/build/a.stamp")));
+        // Generated inputs, including stamp files, are found in the build root.
+        assert_eq!(
+            list["b"].inputs(),
+            &paths(&["/build/a.o", "/build/a.map", "/build/a.stamp", "/src/b.c"])
+        );
+        assert_eq!(list["b"].outputs, paths(&["/abs/b"]));
+        match &list["b"].stdin {
+            Some(Stdin::File(path)) => assert_eq!(path, Path::new("/src/b.in")),
+            stdin => panic!("unexpected stdin {:?}", stdin),
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn stdin_feeds_commands() {
+        let dir = tempdir("stdin");
+        let (input, output) = (within(&dir, "in"), within(&dir, "out"));
+        fs::write(&input, "from a file\n").unwrap();
+        let upper = format!("tr a-z A-Z > '{}'", output);
+        let mut a = target("a", &[&output], &[], &[&upper]);
+        a.stdin = Some(Stdin::File(input.into()));
+        let list = Target::finalize_list(vec![a]);
+        let opts = UpdateOptions::default();
+        assert!(list["a"].update(&list, &opts, &StatCache::new()).unwrap());
+        assert_eq!(fs::read_to_string(&output).unwrap(), "FROM A FILE\n");
+
+        // Text larger than a pipe buffer is fed to concurrent commands, which
+        // only read it once both have started.
+        let (x, y) = (within(&dir, "x"), within(&dir, "y"));
+        let count = |mine: &str, theirs: &str| {
+            format!("{} && wc -c > '{}.count'", rendezvous(mine, theirs), mine)
+        };
+        let mut b = target("b", &[], &[], &[&count(&x, &y), &count(&y, &x)]);
+        b.stdin = Some(Stdin::Text("x".repeat(1 << 20)));
+        b.parallel_commands = true;
+        let list = Target::finalize_list(vec![b]);
+        assert!(list["b"].update(&list, &opts, &StatCache::new()).unwrap());
+        for count in &[x, y] {
+            let count = fs::read_to_string(format!("{}.count", count)).unwrap();
+            assert_eq!(count.trim(), (1 << 20).to_string());
+        }
+    }
+
+    #[test]
+    fn volatile_inputs_always_stale() {
+        let dir = tempdir("volatile");
+        let (input, output) = (within(&dir, "in"), within(&dir, "out"));
+        file(Path::new(&input), Duration::from_secs(100));
+        file(Path::new(&output), Duration::from_secs(10));
+        let with_volatile = |volatile: &[&str]| {
+            let mut a = target("a", &[&output], &[&input], &[]);
+            a.volatile_inputs = volatile.iter().map(|v| v.to_string()).collect();
+            Target::finalize_list(vec![a])
+        };
+
+        let opts = UpdateOptions::default();
+        let fresh = with_volatile(&[]);
+        assert!(fresh["a"]
+            .outdated(&opts, &StatCache::new())
+            .unwrap()
+            .is_none());
+        let forced = with_volatile(&["FORCE"]);
+        for _ in 0..2 {
+            match forced["a"].outdated(&opts, &StatCache::new()).unwrap() {
+                Some(UpdateReason::Volatile(name)) => assert_eq!(name, "FORCE"),
+                reason => panic!("unexpected reason {:?}", reason),
+            }
+        }
+        assert_eq!(
+            forced["a"]
+                .explain(&forced, &opts, &StatCache::new())
+                .unwrap(),
+            "a: needs update (input FORCE is volatile)\n"
+        );
+
+        assert_ne!(
+            fresh["a"].manifest_hash(&fresh),
+            forced["a"].manifest_hash(&forced)
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn cancel_mid_build() {
+        let dir = tempdir("cancel");
+        let (a, c) = (within(&dir, "a"), within(&dir, "c"));
+        let (started, b) = (within(&dir, "b.started"), within(&dir, "b"));
+        let list = Target::finalize_list(vec![
+            target("a", &[&a], &[], &[&format!("touch '{}'", a)]),
+            target(
+                "b",
+                &[&b],
+                &["a"],
+                &[
+                    &format!("touch '{}' && exec sleep 10", started),
+                    &format!("touch '{}'", b),
+                ],
+            ),
+            target("c", &[&c], &["b"], &[&format!("touch '{}'", c)]),
+        ]);
+
+        // Cancel the update once `b` has started running.
+        let cancel = Arc::new(AtomicBool::new(false));
+        let canceller = {
+            let cancel = cancel.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    if Path::new(&started).exists() {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+                cancel.store(true, Ordering::SeqCst);
+            })
+        };
+        let opts = UpdateOptions {
+            cancel: Some(cancel),
+            ..UpdateOptions::default()
+        };
+        let start = SystemTime::now();
+        match list["c"].update(&list, &opts, &StatCache::new()) {
+            Err(UpdateErr::Cancelled) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        canceller.join().unwrap();
+        assert!(start.elapsed().unwrap() < Duration::from_secs(5));
+        assert!(Path::new(&a).exists());
+        assert!(!Path::new(&b).exists());
+        assert!(!Path::new(&c).exists());
+
+        // Nothing is run once cancelled.
+        fs::remove_file(&a).unwrap();
+        assert!(list["a"].update(&list, &opts, &StatCache::new()).is_err());
+        assert!(!Path::new(&a).exists());
+    }
+
+    #[test]
+    fn alias_resolves_to_target() {
+        let mut all = target("all", &[], &[], &[]);
+        all.alias = Some("app".to_string());
+        let mut both = target("both", &[], &["lib"], &[]);
+        both.alias = Some("app".to_string());
+        let list = Target::finalize_list(vec![
+            all,
+            both,
+            target("app", &["app.bin"], &["lib"], &[]),
+            target("lib", &[], &[], &[]),
+        ]);
+        assert_eq!(list["all"].dependencies(), &vec!["app".to_string()]);
+        assert_eq!(
+            list["both"].dependencies(),
+            &vec!["lib".to_string(), "app".to_string()]
+        );
+        assert!(list["all"].inputs().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "refers to missing target")]
+    fn alias_to_missing_target() {
+        let mut all = target("all", &[], &[], &[]);
+        all.alias = Some("app".to_string());
+        Target::finalize_list(vec![all]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cyclic dependency")]
+    fn alias_cycle() {
+        let mut a = target("a", &[], &[], &[]);
+        a.alias = Some("b".to_string());
+        let mut b = target("b", &[], &[], &[]);
+        b.alias = Some("a".to_string());
+        Target::finalize_list(vec![a, b]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cyclic dependency")]
+    fn alias_to_itself() {
+        let mut a = target("a", &[], &[], &[]);
+        a.alias = Some("a".to_string());
+        Target::finalize_list(vec![a]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn update_all_roots() {
+        let dir = tempdir("update-all");
+        let log = within(&dir, "log");
+        let append = |name: &str| format!("echo {} >> '{}'", name, log);
+        let list = Target::finalize_list(vec![
+            target("top", &[], &["left", "right"], &[&append("top")]),
+            target("left", &[], &["bottom"], &[&append("left")]),
+            target("right", &[], &["bottom"], &[&append("right")]),
+            target("bottom", &[], &[], &[&append("bottom")]),
+            target("other", &[], &["bottom"], &[&append("other")]),
+        ]);
+        let roots = Target::roots(&list);
+        let roots = roots.iter().map(|t| t.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(roots, vec!["other", "top"]);
+
+        let opts = UpdateOptions::default();
+        assert!(Target::update_all(&list, false, &opts, &StatCache::new()).unwrap());
+        let lines = fs::read_to_string(&log).unwrap();
+        assert_eq!(lines, "bottom\nother\nleft\nright\ntop\n");
+
+        // Every target, including non-roots, is still only updated once.
+        fs::remove_file(&log).unwrap();
+        assert!(Target::update_all(&list, true, &opts, &StatCache::new()).unwrap());
+        let lines = fs::read_to_string(&log).unwrap();
+        assert_eq!(lines.matches("bottom").count(), 1);
+        assert_eq!(lines.lines().count(), 5);
+    }
+
+    #[test]
+    fn ignore_missing_inputs() {
+        let dir = tempdir("missing-inputs");
+        let (source, generated) = (within(&dir, "main.c"), within(&dir, "gen.h"));
+        file(Path::new(&source), Duration::from_secs(10));
+        let list = Target::finalize_list(vec![target("lint", &[], &[&source, &generated], &[])]);
+
+        let mut opts = UpdateOptions::default();
+        match list["lint"].explain(&list, &opts, &StatCache::new()) {
+            Err(UpdateErr::File { path, .. }) => assert_eq!(path, generated),
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        opts.ignore_missing_inputs = true;
+        assert_eq!(
+            list["lint"]
+                .explain(&list, &opts, &StatCache::new())
+                .unwrap(),
+            format!("lint: needs update (input {} is missing)\n", generated)
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn clean_env() {
+        env::set_var("SAMURAI_TEST_CLEAN_ENV", "set");
+        let run = |command: &str, clean: bool, kept: &[&str]| {
+            let mut a = target("a", &[], &[], &[command]);
+            a.clean_env = clean;
+            a.kept_env = kept.iter().map(|v| v.to_string()).collect();
+            let list = Target::finalize_list(vec![a]);
+            list["a"]
+                .update(&list, &UpdateOptions::default(), &StatCache::new())
+                .is_ok()
+        };
+        let unset = "[ -z \"$SAMURAI_TEST_CLEAN_ENV\" ]";
+        let set = "[ \"$SAMURAI_TEST_CLEAN_ENV\" = set ]";
+
+        assert!(run(set, false, &[]));
+        assert!(run(unset, true, &[]));
+        assert!(!run(set, true, &[]));
+        assert!(run(set, true, &["SAMURAI_TEST_CLEAN_ENV"]));
+        // Variables kept by default still work.
+        assert!(run("[ -n \"$PATH\" ]", true, &[]));
+    }
+
+    #[test]
+    fn overrides_from_files() {
+        let dir = tempdir("overrides");
+        let (input, output) = (within(&dir, "in"), within(&dir, "out"));
+        file(Path::new(&input), Duration::from_secs(100));
+        file(Path::new(&output), Duration::from_secs(50));
+        let list = Target::finalize_list(vec![
+            target("a", &[&output], &[&input], &[]),
+            target("b", &[], &["a"], &[]),
+        ]);
+        let (what_if, old) = (within(&dir, "what-if"), within(&dir, "old"));
+        fs::write(&what_if, format!("\n{}\n\n", input)).unwrap();
+        fs::write(&old, "b\n\n").unwrap();
+
+        let mut opts = UpdateOptions::default();
+        opts.what_if_from(&what_if).unwrap();
+        assert_eq!(opts.what_if, vec![PathBuf::from(&input)]);
+        assert_eq!(
+            list["a"].explain(&list, &opts, &StatCache::new()).unwrap(),
+            format!(
+                "a: needs update (input {} is newer than output {})\n",
+                input, output
+            )
+        );
+
+        opts.assume_old_from(&old).unwrap();
+        assert_eq!(opts.assume_old, vec!["b"]);
+        assert_eq!(
+            list["b"].explain(&list, &opts, &StatCache::new()).unwrap(),
+            "b: assumed up to date\n"
+        );
+
+        assert!(opts.assume_old_from(within(&dir, "missing")).is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn verify_outputs() {
+        let dir = tempdir("verify-outputs");
+        let (made, forgotten) = (within(&dir, "made"), within(&dir, "forgotten"));
+        let list = Target::finalize_list(vec![target(
+            "a",
+            &[&made, &forgotten],
+            &[],
+            &[&format!("touch '{}'", made)],
+        )]);
+
+        let mut opts = UpdateOptions::default();
+        let err = list["a"]
+            .update(&list, &opts, &StatCache::new())
+            .unwrap_err();
+        match &err {
+            UpdateErr::MissingOutput { path } => assert_eq!(path, &forgotten),
+            err => panic!("unexpected error {:?}", err),
+        }
+        assert_eq!(err.code(), 4);
+
+        opts.verify_outputs = false;
+        assert!(list["a"].update(&list, &opts, &StatCache::new()).unwrap());
+        assert!(!Path::new(&forgotten).exists());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn multi_line_commands() {
+        // Shell state persists across the lines of a command, but not
+        // across commands.
+        let list = Target::finalize_list(vec![target(
+            "a",
+            &[],
+            &[],
+            &["X=1\n[ \"$X\" = 1 ]", "[ -z \"$X\" ]"],
+        )]);
+        let opts = UpdateOptions::default();
+        assert!(list["a"].update(&list, &opts, &StatCache::new()).unwrap());
+
+        let list = Target::finalize_list(vec![target("b", &[], &[], &["X=1\n[ \"$X\" = 2 ]"])]);
+        match list["b"].update(&list, &opts, &StatCache::new()) {
+            Err(UpdateErr::Status { status: 1 }) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn names_with_spaces_and_colons() {
+        let list = Target::finalize_list(vec![
+            target("all", &[], &["my target", "a:b", "my input"], &[]),
+            target("my target", &[], &[], &[]),
+            target("a:b", &[], &[], &[]),
+        ]);
+        assert_eq!(
+            list["all"].dependencies(),
+            &vec!["my target".to_string(), "a:b".to_string()]
+        );
+        assert_eq!(list["all"].inputs(), &vec![PathBuf::from("my input")]);
+        assert_eq!(Target::lookup(&list, "a:b").unwrap().name, "a:b");
+    }
+
+    #[test]
+    fn seeded_mtimes() {
+        let dir = tempdir("seeded");
+        let (input, output) = (within(&dir, "in"), within(&dir, "out"));
+        file(Path::new(&input), Duration::from_secs(50));
+        file(Path::new(&output), Duration::from_secs(10));
+        let list = Target::finalize_list(vec![target("a", &[&output], &[&input], &[])]);
+        let opts = UpdateOptions::default();
+        assert!(list["a"]
+            .outdated(&opts, &StatCache::new())
+            .unwrap()
+            .is_none());
+
+        // The recorded time of the output is used instead of its real one.
+        let recorded = SystemTime::now() - Duration::from_secs(100);
+        let cache = StatCache::with_mtimes(vec![(PathBuf::from(&output), recorded)]);
+        match list["a"].outdated(&opts, &cache).unwrap() {
+            Some(UpdateReason::NewerInput { output: o, .. }) => assert_eq!(o, Path::new(&output)),
+            reason => panic!("unexpected reason {:?}", reason),
+        }
+        assert_eq!(cache.modified(Path::new(&output)), Some(recorded));
+
+        // Other files are read from the filesystem.
+        let real = fs::metadata(&input).unwrap().modified().unwrap();
+        assert_eq!(cache.modified(Path::new(&input)), Some(real));
+
+        // Forgotten times are read again.
+        cache.forget(Some(&PathBuf::from(&output)));
+        assert!(list["a"].outdated(&opts, &cache).unwrap().is_none());
+    }
+
+    #[test]
+    fn shared_input_read_once() {
+        let dir = tempdir("shared-input");
+        let header = within(&dir, "common.h");
+        let (a, b) = (within(&dir, "a.o"), within(&dir, "b.o"));
+        file(Path::new(&header), Duration::from_secs(50));
+        file(Path::new(&a), Duration::from_secs(10));
+        file(Path::new(&b), Duration::from_secs(10));
+        let list = Target::finalize_list(vec![
+            target("a", &[&a], &[&header], &[]),
+            target("b", &[&b], &[&header], &[]),
+        ]);
+
+        let (opts, cache) = (UpdateOptions::default(), StatCache::new());
+        assert!(list["a"].outdated(&opts, &cache).unwrap().is_none());
+        // The header changes, but its time was already read during this run.
+        file(Path::new(&header), Duration::from_secs(0));
+        assert!(list["b"].outdated(&opts, &cache).unwrap().is_none());
+        // A new run sees the change.
+        assert!(list["b"]
+            .outdated(&opts, &StatCache::new())
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn explain_missing_output() {
+        let dir = tempdir("explain");
+        let (input, output) = (within(&dir, "b.in"), within(&dir, "b.out"));
+        let missing = within(&dir, "a.out");
+        file(Path::new(&input), Duration::from_secs(50));
+        file(Path::new(&output), Duration::from_secs(10));
+        let list = Target::finalize_list(vec![
+            target("top", &[], &["a", "b"], &[]),
+            target("a", &[&missing], &["b"], &[]),
+            target("b", &[&output], &[&input], &[]),
+        ]);
+
+        let explanation = list["top"]
+            .explain(&list, &UpdateOptions::default(), &StatCache::new())
+            .unwrap();
+        assert_eq!(
+            explanation,
+            format!(
+                concat!(
+                    "top: needs update (dependency a needs update)\n",
+                    "  a: needs update (output {} is missing)\n",
+                    "    b: up to date\n",
+                    "  b: up to date (see above)\n",
+                ),
+                missing
+            )
+        );
+    }
+
+    #[test]
+    fn clean_outputs() {
+        let dir = tempdir("clean");
+        let root = dir.join("root");
+        fs::create_dir(&root).unwrap();
+        let (input, a, b) = (
+            within(&root, "a.in"),
+            within(&root, "a.out"),
+            within(&root, "b.out"),
+        );
+        let stamp = within(&root, "a.stamp");
+        for path in &[&input, &a, &b, &stamp] {
+            file(Path::new(path), Duration::from_secs(10));
+        }
+        let mut first = target("a", &[&a], &[&input], &[]);
+        first.stamp = Some(stamp.clone().into());
+        let second = target("b", &[&b, &within(&root, "missing")], &["a"], &[]);
+        let list = Target::finalize_list(vec![first, second]);
+
+        let removed = list["b"].clean(&list, &root, false).unwrap();
+        let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+        assert_eq!(removed, paths(&[&a, &stamp, &b]));
+        assert!(removed.iter().all(|path| !path.exists()));
+        assert!(Path::new(&input).exists());
+
+        // Nothing is removed if an output lies outside of the root.
+        let (inside, outside) = (within(&root, "c.out"), within(&dir, "c.out"));
+        file(Path::new(&inside), Duration::from_secs(10));
+        file(Path::new(&outside), Duration::from_secs(10));
+        let list = Target::finalize_list(vec![target("c", &[&inside, &outside], &[], &[])]);
+        let err = list["c"].clean(&list, &root, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(Path::new(&inside).exists() && Path::new(&outside).exists());
+
+        let removed = list["c"].clean(&list, &root, true).unwrap();
+        assert_eq!(removed, paths(&[&inside, &outside]));
+        assert!(!Path::new(&inside).exists() && !Path::new(&outside).exists());
+    }
+}
@@ -0,0 +1,77 @@
+//! Utilities for presenting information to humans.
+//!
+//! These are intended for reports and other human-readable output only.
+//! Machine-readable output should use raw units instead.
+
+use std::time::Duration;
+
+/// Formats a duration in a human-friendly manner.
+///
+/// Durations under a second are given in milliseconds (e.g `450ms`), under a
+/// minute in seconds with one decimal (e.g `12.3s`), under an hour in minutes
+/// and seconds (e.g `1m 23s`), and otherwise in hours and minutes (e.g
+/// `2h 5m`).
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs == 0 {
+        format!("{}ms", duration.subsec_millis())
+    } else if secs < 60 {
+        format!("{}.{}s", secs, duration.subsec_millis() / 100)
+    } else if secs < 60 * 60 {
+        format!("{}m {}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h {}m", secs / (60 * 60), secs / 60 % 60)
+    }
+}
+
+/// Formats a size in bytes in a human-friendly manner.
+///
+/// Sizes under 1 KiB are given in bytes (e.g `512 B`), and larger sizes with
+/// one decimal in the largest fitting binary unit, up to TiB (e.g `1.2 MiB`).
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    // Move to the next unit whenever the size would round up to 1024.0 in
+    // the current one.
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 - 0.05 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(Duration::from_millis(0)), "0ms");
+        assert_eq!(format_duration(Duration::from_millis(999)), "999ms");
+        assert_eq!(format_duration(Duration::from_millis(1000)), "1.0s");
+        assert_eq!(format_duration(Duration::from_millis(12_345)), "12.3s");
+        assert_eq!(format_duration(Duration::from_secs(83)), "1m 23s");
+        assert_eq!(
+            format_duration(Duration::from_secs(2 * 3600 + 5 * 60)),
+            "2h 5m"
+        );
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1024), "1.0 KiB");
+        assert_eq!(format_size(1_048_524), "1023.9 KiB");
+        assert_eq!(format_size(1_048_575), "1.0 MiB");
+        assert_eq!(format_size(1_048_576), "1.0 MiB");
+        assert_eq!(format_size(1 << 40), "1.0 TiB");
+        assert_eq!(format_size(1 << 50), "1024.0 TiB");
+    }
+}